@@ -138,36 +138,36 @@ fn main() -> ! {
     // hprintln!("{:x}", x);
 
     dbg!("before erasing");
-    hprintln!("{:#034x}", flash.read_u128(0x4_0000));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0000).unwrap());
     const WHERE: usize = 0x0004_0000; // 256kB offset
 
     dbg!("after erasing");
     flash.erase_page(WHERE >> 4).unwrap();
-    hprintln!("{:#034x}", flash.read_u128(0x4_0000));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0000).unwrap());
 
     dbg!("after writing");
     flash.write_u32(WHERE, 0x1234_5678).unwrap();
-    hprintln!("{:#034x}", flash.read_u128(0x4_0000));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0000).unwrap());
 
     dbg!("after erasing again");
     flash.erase_page(WHERE >> 4).unwrap();
-    hprintln!("{:#034x}", flash.read_u128(0x4_0000));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0000).unwrap());
 
     dbg!("after writing with offset 4");
     flash.write_u32(WHERE + 4, 0x1234_5678).unwrap();
-    hprintln!("{:#034x}", flash.read_u128(0x4_0000));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0000).unwrap());
 
-    hprintln!("{:#034x}", flash.read_u128(0x4_0010));
-    hprintln!("{:#034x}", flash.read_u128(0x4_0020));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0010).unwrap());
+    hprintln!("{:#034x}", flash.read_u128(0x4_0020).unwrap());
 
     let mut read_buf = [0u8; 16];
-    flash.read(WHERE, &mut read_buf);
+    flash.read(WHERE, &mut read_buf).unwrap();
     // dbg!(read_buf);
 
     flash.erase_page(0x4_0200).unwrap();
     hprintln!("supposedly erased");
     // dbg!(flash.status());
-    flash.read(WHERE, &mut read_buf);
+    flash.read(WHERE, &mut read_buf).unwrap();
     // dbg!(read_buf);
 
     let data: [u8; 4] = [0x7, 0x2, 0x3, 0x4];
@@ -183,7 +183,7 @@ fn main() -> ! {
         .write_native(WHERE, generic_array::GenericArray::from_slice(&buf))
         .unwrap();
     flash.write_u8(0x4_000F, 69).ok();
-    flash.read(WHERE, &mut read_buf);
+    flash.read(WHERE, &mut read_buf).unwrap();
     // dbg!(read_buf);
 
     // flash.clear_page_register();
@@ -192,20 +192,20 @@ fn main() -> ! {
     // flash.read(0x4_0200, &mut read_buf);
     // dbg!(read_buf);
     // flash.write_u32(0x4_0200, 32).ok();
-    // hprintln!("{:#x}", flash.read_u128(0x4_0200));
+    // hprintln!("{:#x}", flash.read_u128(0x4_0200).unwrap());
     // flash.read(0x4_0200, &mut read_buf);
     // dbg!(read_buf);
     // // flash.write_u8(0x4_0206, 64).ok();
     // flash.write_u32(0x4_0204, 128).ok();
-    // hprintln!("{:#x}", flash.read_u128(0x4_0200));
+    // hprintln!("{:#x}", flash.read_u128(0x4_0200).unwrap());
     // flash.read(0x4_0200, &mut read_buf);
     // dbg!(read_buf);
     // // flash.read(0x4_0210, &mut read_buf);
     // // dbg!(read_buf);
 
-    hprintln!("{:#034x}", flash.read_u128(0x4_0200));
-    hprintln!("{:#034x}", flash.read_u128(0x4_0210));
-    hprintln!("{:#034x}", flash.read_u128(0x4_0220));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0200).unwrap());
+    hprintln!("{:#034x}", flash.read_u128(0x4_0210).unwrap());
+    hprintln!("{:#034x}", flash.read_u128(0x4_0220).unwrap());
 
     flash.write_u128(0x4_0200, 0x1234567).unwrap();
     // hal::wait_at_least(1_000_000);
@@ -213,9 +213,9 @@ fn main() -> ! {
     // hal::wait_at_least(1_000_000);
     // flash.write_u128(0x4_0200, 0x1234567).unwrap();
 
-    hprintln!("{:#034x}", flash.read_u128(0x4_0200));
-    hprintln!("{:#034x}", flash.read_u128(0x4_0210));
-    hprintln!("{:#034x}", flash.read_u128(0x4_0220));
+    hprintln!("{:#034x}", flash.read_u128(0x4_0200).unwrap());
+    hprintln!("{:#034x}", flash.read_u128(0x4_0210).unwrap());
+    hprintln!("{:#034x}", flash.read_u128(0x4_0220).unwrap());
 
     hprintln!("loop-continue");
     loop {
@@ -0,0 +1,62 @@
+#![no_main]
+#![no_std]
+/// Provision the PUF key store once, then derive its keys at every boot.
+///
+/// Provisioning writes to CMPA and can be done only once per device:
+/// run this example, reset the board, and run it again.
+extern crate panic_semihosting; // 4004 bytes
+                                // extern crate panic_halt; // 672 bytes
+
+use cortex_m_rt::entry;
+use cortex_m_semihosting::{heprint, heprintln};
+
+use hal::{drivers::PufKeyStore, peripherals::pfr::KeyType, prelude::*};
+use lpc55_hal as hal;
+
+macro_rules! dump_hex {
+    ($array:expr, $length:expr ) => {
+        heprint!("{:?} = ", stringify!($array));
+        for i in 0..$length {
+            heprint!("{:02X}", $array[i]);
+        }
+        heprintln!("");
+    };
+}
+
+#[entry]
+fn main() -> ! {
+    let hal = hal::new();
+
+    let mut anactrl = hal.anactrl;
+    let mut pmc = hal.pmc;
+    let mut syscon = hal.syscon;
+
+    let clocks = hal::ClockRequirements::default()
+        .system_frequency(96.MHz())
+        .configure(&mut anactrl, &mut pmc, &mut syscon)
+        .unwrap();
+
+    let pfr = hal.pfr.enabled(&clocks).unwrap();
+    let puf = hal.puf.enabled(&mut syscon).unwrap();
+    let mut key_store = PufKeyStore::new(puf, pfr);
+
+    if !key_store.is_provisioned() {
+        heprintln!("Provisioning the key store...");
+        key_store.provision().unwrap();
+        heprintln!("Done, now reset the board to derive the keys.");
+    } else {
+        // the ROM may already have started the PUF, `start` copes with that
+        let mut key_store = key_store.start().unwrap();
+
+        heprintln!("Loading SBKEK and PRINCE keys into their IPs");
+        key_store.load_key(KeyType::Sbkek).unwrap();
+        key_store.load_key(KeyType::PrinceRegion0).unwrap();
+
+        let user_key = key_store.key(KeyType::User).unwrap();
+        dump_hex!(user_key, 32);
+    }
+
+    loop {
+        continue;
+    }
+}
@@ -11,7 +11,9 @@ use cortex_m_semihosting::dbg;
 use cortex_m_semihosting::heprint;
 use cortex_m_semihosting::heprintln;
 
+use hal::peripherals::puf::{ActivationCode, KeyCode};
 use hal::prelude::*;
+use hal::traits::digest::generic_array::typenum::U32;
 use lpc55_hal as hal;
 
 /// PUF error
@@ -52,14 +54,9 @@ fn main() -> ! {
     dbg!(&puf);
 
     let mut buffer = [0u8; 16];
-    flash.read(PUF_STATE_FLASH, &mut buffer);
+    flash.read(PUF_STATE_FLASH, &mut buffer).unwrap();
 
     let state: u32 = u32::from_ne_bytes(buffer[0..4].try_into().unwrap());
-    let mut ac = [0u8; 1192];
-    let mut kc1 = [0u8; 52];
-    let mut kc2 = [0u8; 52];
-    let mut kc3 = [0u8; 52];
-    let mut kc4 = [0u8; 52];
     let mut check_buf = [0u8; 1192 + 52 * 4];
 
     if state != (State::Enrolled as u32) {
@@ -67,7 +64,8 @@ fn main() -> ! {
         dbg!("enrolling...");
         let mut write_buf = [0u8; 512];
 
-        let puf_enrolled = puf.enroll(&mut ac).unwrap();
+        let (puf_enrolled, ac) = puf.enroll().unwrap();
+        let ac = ac.as_bytes();
 
         dbg!(&puf_enrolled);
 
@@ -75,10 +73,16 @@ fn main() -> ! {
         dump_hex!(ac[1192 - 16..], 16);
 
         dbg!("Generate 2 IP-direct keys, and 2 normal keys.");
-        puf_enrolled.generate_key(256, 0, &mut kc1).unwrap();
-        puf_enrolled.generate_key(256, 0, &mut kc2).unwrap();
-        puf_enrolled.generate_key(256, 1, &mut kc3).unwrap();
-        puf_enrolled.generate_key(256, 2, &mut kc4).unwrap();
+        let kc1: KeyCode<U32> = puf_enrolled.generate_key(0).unwrap();
+        let kc2: KeyCode<U32> = puf_enrolled.generate_key(0).unwrap();
+        let kc3: KeyCode<U32> = puf_enrolled.generate_key(1).unwrap();
+        let kc4: KeyCode<U32> = puf_enrolled.generate_key(2).unwrap();
+        let (kc1, kc2, kc3, kc4) = (
+            kc1.as_bytes(),
+            kc2.as_bytes(),
+            kc3.as_bytes(),
+            kc4.as_bytes(),
+        );
 
         // Print the 32 bit header + 32 bit of data for curiousity
         dump_hex!(kc1[0..8], 8);
@@ -102,14 +106,14 @@ fn main() -> ! {
 
         // write 3rd chunk, with 4 KC's appended
         write_buf[0..184].copy_from_slice(&ac[1008..1192]);
-        write_buf[184..236].copy_from_slice(&kc1);
-        write_buf[236..288].copy_from_slice(&kc2);
-        write_buf[288..340].copy_from_slice(&kc3);
-        write_buf[340..392].copy_from_slice(&kc4);
+        write_buf[184..236].copy_from_slice(kc1);
+        write_buf[236..288].copy_from_slice(kc2);
+        write_buf[288..340].copy_from_slice(kc3);
+        write_buf[340..392].copy_from_slice(kc4);
         flash.write(PUF_STATE_FLASH + 1024, &write_buf).unwrap();
 
         dbg!("Reading back...");
-        flash.read(PUF_STATE_FLASH + 16, &mut check_buf);
+        flash.read(PUF_STATE_FLASH + 16, &mut check_buf).unwrap();
         dump_hex!(check_buf[..16], 16);
         dump_hex!(check_buf[1192 - 16..], 16);
         for i in 0..ac.len() {
@@ -132,15 +136,15 @@ fn main() -> ! {
         dbg!("Now restart this program to derive the keys.");
     } else {
         dbg!("The device is already enrolled.");
-        flash.read(PUF_STATE_FLASH + 16, &mut check_buf);
-        ac.copy_from_slice(&check_buf[..1192]);
-
-        kc1.copy_from_slice(&check_buf[1192..][..52]);
-        kc2.copy_from_slice(&check_buf[1192 + 52..][..52]);
-        kc3.copy_from_slice(&check_buf[1192 + 52 * 2..][..52]);
-        kc4.copy_from_slice(&check_buf[1192 + 52 * 3..][..52]);
-        dump_hex!(ac[..16], 16);
-        dump_hex!(ac[1192 - 16..], 16);
+        flash.read(PUF_STATE_FLASH + 16, &mut check_buf).unwrap();
+        let ac = ActivationCode::from_bytes(&check_buf[..1192]).unwrap();
+
+        let kc1 = KeyCode::<U32>::from_bytes(&check_buf[1192..][..52]).unwrap();
+        let kc2 = KeyCode::<U32>::from_bytes(&check_buf[1192 + 52..][..52]).unwrap();
+        let kc3 = KeyCode::<U32>::from_bytes(&check_buf[1192 + 52 * 2..][..52]).unwrap();
+        let kc4 = KeyCode::<U32>::from_bytes(&check_buf[1192 + 52 * 3..][..52]).unwrap();
+        dump_hex!(ac.as_bytes()[..16], 16);
+        dump_hex!(ac.as_bytes()[1192 - 16..], 16);
 
         let puf_started = puf.start(&ac).unwrap();
 
@@ -10,7 +10,10 @@ pub mod prelude {
 }
 
 pub mod aes;
-pub use aes::{Aes, Key as AesKey};
+pub use aes::{Aes, AesCbc, AesCcm, AesCmac, AesCtr, AesGcm, Key as AesKey};
+
+pub mod casper;
+pub use casper::{CasperArithmetic, CasperEngine, CasperP256, CasperRsa, RsaPublicKey};
 
 pub mod clocks;
 pub use clocks::ClockRequirements;
@@ -19,7 +22,7 @@ pub mod pins;
 pub use pins::{Pin, Pins};
 
 pub mod flash;
-pub use flash::FlashGordon;
+pub use flash::{FlashGordon, FlashRegion};
 
 pub mod gint;
 pub use gint::GroupInterrupt;
@@ -27,6 +30,9 @@ pub use gint::GroupInterrupt;
 pub mod i2c;
 pub use i2c::I2cMaster;
 
+pub mod keystore;
+pub use keystore::PufKeyStore;
+
 pub mod pwm;
 pub use pwm::Pwm;
 
@@ -39,7 +45,7 @@ pub use serial::Serial;
 pub mod rng;
 
 pub mod sha;
-pub use sha::{Sha1, Sha256};
+pub use sha::{HmacSha256, Sha1, Sha256, Sha384, Sha512, ShaState};
 
 pub mod usbd;
 pub use usbd::UsbBus;
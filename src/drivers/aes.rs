@@ -1,4 +1,9 @@
-use core::convert::TryInto;
+//! AES using the HASHCRYPT peripheral.
+//!
+//! `Aes` implements the block cipher (ECB), `AesCbc` and `AesCtr` let the
+//! hardware do the chaining. CFB and OFB are not supported by the hardware.
+
+use core::{cmp::min, convert::TryInto, ptr};
 
 use crate::traits::aligned::{Aligned, A4};
 
@@ -7,7 +12,7 @@ use crate::{
     traits::{
         cipher::{Block, BlockCipher, BlockDecrypt, BlockEncrypt},
         digest::generic_array::{
-            typenum::{U1, U16, U24, U32},
+            typenum::{U16, U24, U32, U8},
             GenericArray,
         },
     },
@@ -25,9 +30,20 @@ mod sealed {
     impl KeySize for super::U32 {}
 }
 
-use cipher::{BlockBackend, BlockSizeUser, ParBlocksSizeUser};
+use cipher::{
+    inout::{InOut, InOutBuf},
+    BlockBackend, BlockDecryptMut, BlockEncryptMut, BlockSizeUser, ParBlocks, ParBlocksSizeUser,
+    StreamCipher, StreamCipherError,
+};
 use sealed::KeySize;
 
+pub mod ccm;
+pub mod cmac;
+pub mod gcm;
+pub use ccm::{AesCcm, Ccm};
+pub use cmac::{AesCmac, Cmac};
+pub use gcm::{AesGcm, Gcm};
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Key<Size: KeySize> {
     Puf,
@@ -40,6 +56,19 @@ pub enum Mode {
     Decrypt,
 }
 
+// blocks staged at once if the input is not word-aligned
+const PAR_BLOCKS: usize = 8;
+// limit of MEMCTRL.COUNT
+const MAX_BLOCKS: usize = 0x7ff;
+
+// how blocks are chained by the hardware
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Chaining {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
 pub type Aes128Key = Key<U16>;
 pub type Aes192Key = Key<U24>;
 pub type Aes256Key = Key<U32>;
@@ -61,7 +90,7 @@ impl<'a, Size: KeySize> Aes<'a, Size> {
             inner: hashcrypt,
             key,
         };
-        aes.configure(mode);
+        aes.configure(mode, Chaining::Ecb, None);
         aes
     }
 
@@ -77,27 +106,33 @@ impl<'a, Size: KeySize> Aes<'a, Size> {
 
     /// Optionally, configure peripheral for decryption ahead of time.
     pub fn prime_for_decryption(&self) {
-        self.configure(Mode::Encrypt);
+        self.configure(Mode::Decrypt, Chaining::Ecb, None);
     }
 
     /// Optionally, configure peripheral for encryption ahead of time.
     pub fn prime_for_encryption(&self) {
-        self.configure(Mode::Encrypt);
+        self.configure(Mode::Encrypt, Chaining::Ecb, None);
     }
 
     // TODO: It seems like it's not possible to switch the `cryptcfg.aesdecrypt` flag
     // after setup. Perhaps there is a magic incantation of register fiddling to achieve
     // this, which besides the context-switching cost would avoid having to store the
     // key inside the struct.
-    fn configure(&self, mode: Mode) {
+    //
+    // The IV is only used for CBC and CTR.
+    fn configure(&self, mode: Mode, chaining: Chaining, iv: Option<&Block<Self>>) {
         //
         // CRYPTCFG
         //
 
         self.cryptcfg.write(|w| {
-            let mut w = w
-                .aesmode()
-                .ecb()
+            let mut w = match chaining {
+                Chaining::Ecb => w.aesmode().ecb(),
+                Chaining::Cbc => w.aesmode().cbc(),
+                // 16 bit counter in the last two bytes of the IV
+                Chaining::Ctr => unsafe { w.aesmode().ctr().aesctrpos().bits(0) },
+            };
+            w = w
                 .msw1st_out()
                 .set_bit()
                 .swapkey()
@@ -159,25 +194,88 @@ impl<'a, Size: KeySize> Aes<'a, Size> {
         }
 
         assert!(self.status.read().needkey().is_not_need());
+
+        //
+        // IV
+        //
+
+        if let Some(iv) = iv {
+            let iv: Aligned<A4, Block<Self>> = Aligned(*iv);
+            self.indata
+                .write(|w| unsafe { w.bits(u32::from_le_bytes(iv[..4].try_into().unwrap())) });
+            for (i, chunk) in iv[4..].chunks(4).enumerate() {
+                self.alias[i]
+                    .write(|w| unsafe { w.bits(u32::from_le_bytes(chunk.try_into().unwrap())) });
+            }
+            assert!(self.status.read().neediv().is_not_need());
+        }
     }
 
-    fn one_block(&self, mut block: cipher::inout::InOut<'_, '_, Block<Self>>) {
-        let block = block.get_out();
-        // needs to be word-aligned
-        let aligned_block: Aligned<A4, Block<Self>> = Aligned(*block);
-        let addr: u32 = &aligned_block as *const _ as _;
+    // whether the peripheral is configured as given
+    fn is_configured(&self, mode: Mode, chaining: Chaining) -> bool {
+        let cryptcfg = self.cryptcfg.read();
+        let aesmode = match chaining {
+            Chaining::Ecb => cryptcfg.aesmode().is_ecb(),
+            Chaining::Cbc => cryptcfg.aesmode().is_cbc(),
+            Chaining::Ctr => cryptcfg.aesmode().is_ctr(),
+        };
+        let aesdecrypt = match mode {
+            Mode::Encrypt => cryptcfg.aesdecrypt().is_encrypt(),
+            Mode::Decrypt => cryptcfg.aesdecrypt().is_decrypt(),
+        };
+        aesmode && aesdecrypt
+    }
 
-        self.memaddr.write(|w| unsafe { w.bits(addr) });
-        self.memctrl
-            .write(|w| unsafe { w.master().enabled().count().bits(1) });
+    fn process(&self, input: &Block<Self>) -> Block<Self> {
+        let mut output = Block::<Self>::default();
+        unsafe { self.process_blocks(input, &mut output, 1) };
+        output
+    }
 
-        while self.status.read().digest().is_not_ready() {
-            continue;
-        }
+    // Process `count` blocks from `input` to `output`, which may be the same.
+    //
+    // Word-aligned input is read by the peripheral directly, up to
+    // `MAX_BLOCKS` per transfer; otherwise it is staged through an aligned
+    // buffer, `PAR_BLOCKS` at a time.
+    //
+    // Safety: `input` and `output` must be valid for `count` blocks.
+    unsafe fn process_blocks(
+        &self,
+        input: *const Block<Self>,
+        output: *mut Block<Self>,
+        count: usize,
+    ) {
+        let mut staged: Aligned<A4, [Block<Self>; PAR_BLOCKS]> = Aligned(Default::default());
+        let mut done = 0;
+        while done < count {
+            let source = input.add(done);
+            let (addr, n) = if (source as usize) % 4 == 0 {
+                (source as u32, min(count - done, MAX_BLOCKS))
+            } else {
+                let n = min(count - done, PAR_BLOCKS);
+                ptr::copy_nonoverlapping(source, staged.as_mut_ptr(), n);
+                (staged.as_ptr() as u32, n)
+            };
+
+            self.memaddr.write(|w| w.bits(addr));
+            self.memctrl
+                .write(|w| w.master().enabled().count().bits(n as u16));
+
+            // the peripheral raises DIGEST for every block, and only continues
+            // once the output is read
+            for i in 0..n {
+                while self.status.read().digest().is_not_ready() {
+                    continue;
+                }
 
-        for i in 0..4 {
-            block.as_mut_slice()[4 * i..4 * i + 4]
-                .copy_from_slice(&self.digest0[i].read().bits().to_be_bytes());
+                let mut block = Block::<Self>::default();
+                for j in 0..4 {
+                    block.as_mut_slice()[4 * j..4 * j + 4]
+                        .copy_from_slice(&self.digest0[j].read().bits().to_be_bytes());
+                }
+                ptr::write(output.add(done + i), block);
+            }
+            done += n;
         }
     }
 }
@@ -190,7 +288,7 @@ struct AesDecrypt<'a, 'b, Size: KeySize>(&'a Aes<'b, Size>);
 impl<Size: KeySize> BlockCipher for Aes<'_, Size> {}
 
 impl<Size: KeySize> ParBlocksSizeUser for Aes<'_, Size> {
-    type ParBlocksSize = U1;
+    type ParBlocksSize = U8;
 }
 
 impl<Size: KeySize> BlockSizeUser for Aes<'_, Size> {
@@ -202,33 +300,77 @@ impl<Size: KeySize> BlockSizeUser for AesEncrypt<'_, '_, Size> {
 }
 
 impl<Size: KeySize> ParBlocksSizeUser for AesEncrypt<'_, '_, Size> {
-    type ParBlocksSize = U1;
+    type ParBlocksSize = U8;
 }
 
 impl<Size: KeySize> BlockSizeUser for AesDecrypt<'_, '_, Size> {
     type BlockSize = U16;
 }
 impl<Size: KeySize> ParBlocksSizeUser for AesDecrypt<'_, '_, Size> {
-    type ParBlocksSize = U1;
+    type ParBlocksSize = U8;
 }
 
-impl<Size: KeySize> BlockBackend for AesEncrypt<'_, '_, Size> {
-    fn proc_block(&mut self, block: cipher::inout::InOut<'_, '_, Block<Self>>) {
+impl<Size: KeySize> AesEncrypt<'_, '_, Size> {
+    unsafe fn proc_raw(
+        &mut self,
+        input: *const Block<Self>,
+        output: *mut Block<Self>,
+        count: usize,
+    ) {
         // unfortunate implementation detail
-        if self.0.cryptcfg.read().aesdecrypt().is_decrypt() {
-            self.0.configure(Mode::Encrypt);
+        if !self.0.is_configured(Mode::Encrypt, Chaining::Ecb) {
+            self.0.configure(Mode::Encrypt, Chaining::Ecb, None);
         }
-        self.0.one_block(block);
+        self.0.process_blocks(input, output, count);
     }
 }
 
-impl<Size: KeySize> BlockBackend for AesDecrypt<'_, '_, Size> {
-    fn proc_block(&mut self, block: cipher::inout::InOut<'_, '_, Block<Self>>) {
+impl<Size: KeySize> BlockBackend for AesEncrypt<'_, '_, Size> {
+    fn proc_block(&mut self, block: InOut<'_, '_, Block<Self>>) {
+        let (input, output) = block.into_raw();
+        unsafe { self.proc_raw(input, output, 1) }
+    }
+
+    fn proc_par_blocks(&mut self, blocks: InOut<'_, '_, ParBlocks<Self>>) {
+        self.proc_tail_blocks(blocks.into_buf());
+    }
+
+    fn proc_tail_blocks(&mut self, blocks: InOutBuf<'_, '_, Block<Self>>) {
+        let count = blocks.len();
+        let (input, output) = blocks.into_raw();
+        unsafe { self.proc_raw(input, output, count) }
+    }
+}
+
+impl<Size: KeySize> AesDecrypt<'_, '_, Size> {
+    unsafe fn proc_raw(
+        &mut self,
+        input: *const Block<Self>,
+        output: *mut Block<Self>,
+        count: usize,
+    ) {
         // unfortunate implementation detail
-        if self.0.cryptcfg.read().aesdecrypt().is_encrypt() {
-            self.0.configure(Mode::Decrypt);
+        if !self.0.is_configured(Mode::Decrypt, Chaining::Ecb) {
+            self.0.configure(Mode::Decrypt, Chaining::Ecb, None);
         }
-        self.0.one_block(block);
+        self.0.process_blocks(input, output, count);
+    }
+}
+
+impl<Size: KeySize> BlockBackend for AesDecrypt<'_, '_, Size> {
+    fn proc_block(&mut self, block: InOut<'_, '_, Block<Self>>) {
+        let (input, output) = block.into_raw();
+        unsafe { self.proc_raw(input, output, 1) }
+    }
+
+    fn proc_par_blocks(&mut self, blocks: InOut<'_, '_, ParBlocks<Self>>) {
+        self.proc_tail_blocks(blocks.into_buf());
+    }
+
+    fn proc_tail_blocks(&mut self, blocks: InOutBuf<'_, '_, Block<Self>>) {
+        let count = blocks.len();
+        let (input, output) = blocks.into_raw();
+        unsafe { self.proc_raw(input, output, count) }
     }
 }
 
@@ -256,3 +398,247 @@ impl<Size: KeySize> core::ops::DerefMut for Aes<'_, Size> {
         self.inner
     }
 }
+
+/// AES in CBC mode, chained by the hardware.
+///
+/// The IV is also tracked in software, so switching between encryption
+/// and decryption continues the chain.
+pub struct AesCbc<'a, Size: KeySize> {
+    aes: Aes<'a, Size>,
+    iv: Block<Aes<'a, Size>>,
+    // direction the hardware chain is set up for
+    configured: Option<Mode>,
+}
+
+impl<'a, Size: KeySize> AesCbc<'a, Size> {
+    pub fn new(
+        hashcrypt: &'a mut Hashcrypt<Enabled>,
+        key: Key<Size>,
+        iv: &GenericArray<u8, U16>,
+    ) -> Self {
+        Self {
+            aes: Aes {
+                inner: hashcrypt,
+                key,
+            },
+            iv: *iv,
+            configured: None,
+        }
+    }
+
+    /// The IV for the next block, i.e. the last ciphertext block.
+    pub fn iv(&self) -> GenericArray<u8, U16> {
+        self.iv
+    }
+
+    pub fn release(self) -> &'a mut Hashcrypt<Enabled> {
+        self.aes.inner
+    }
+
+    fn prepare(&mut self, mode: Mode) {
+        if self.configured != Some(mode) || !self.aes.is_configured(mode, Chaining::Cbc) {
+            self.aes.configure(mode, Chaining::Cbc, Some(&self.iv));
+            self.configured = Some(mode);
+        }
+    }
+}
+
+struct CbcEncrypt<'a, 'b, Size: KeySize>(&'a mut AesCbc<'b, Size>);
+struct CbcDecrypt<'a, 'b, Size: KeySize>(&'a mut AesCbc<'b, Size>);
+
+impl<Size: KeySize> BlockSizeUser for AesCbc<'_, Size> {
+    type BlockSize = U16;
+}
+
+impl<Size: KeySize> BlockSizeUser for CbcEncrypt<'_, '_, Size> {
+    type BlockSize = U16;
+}
+
+impl<Size: KeySize> ParBlocksSizeUser for CbcEncrypt<'_, '_, Size> {
+    type ParBlocksSize = U8;
+}
+
+impl<Size: KeySize> BlockSizeUser for CbcDecrypt<'_, '_, Size> {
+    type BlockSize = U16;
+}
+
+impl<Size: KeySize> ParBlocksSizeUser for CbcDecrypt<'_, '_, Size> {
+    type ParBlocksSize = U8;
+}
+
+impl<Size: KeySize> BlockBackend for CbcEncrypt<'_, '_, Size> {
+    fn proc_block(&mut self, block: InOut<'_, '_, Block<Self>>) {
+        let (input, output) = block.into_raw();
+        unsafe { self.proc_raw(input, output, 1) }
+    }
+
+    fn proc_par_blocks(&mut self, blocks: InOut<'_, '_, ParBlocks<Self>>) {
+        self.proc_tail_blocks(blocks.into_buf());
+    }
+
+    fn proc_tail_blocks(&mut self, blocks: InOutBuf<'_, '_, Block<Self>>) {
+        let count = blocks.len();
+        let (input, output) = blocks.into_raw();
+        unsafe { self.proc_raw(input, output, count) }
+    }
+}
+
+impl<Size: KeySize> CbcEncrypt<'_, '_, Size> {
+    unsafe fn proc_raw(
+        &mut self,
+        input: *const Block<Self>,
+        output: *mut Block<Self>,
+        count: usize,
+    ) {
+        if count == 0 {
+            return;
+        }
+        self.0.prepare(Mode::Encrypt);
+        self.0.aes.process_blocks(input, output, count);
+        self.0.iv = *output.add(count - 1);
+    }
+}
+
+impl<Size: KeySize> BlockBackend for CbcDecrypt<'_, '_, Size> {
+    fn proc_block(&mut self, block: InOut<'_, '_, Block<Self>>) {
+        let (input, output) = block.into_raw();
+        unsafe { self.proc_raw(input, output, 1) }
+    }
+
+    fn proc_par_blocks(&mut self, blocks: InOut<'_, '_, ParBlocks<Self>>) {
+        self.proc_tail_blocks(blocks.into_buf());
+    }
+
+    fn proc_tail_blocks(&mut self, blocks: InOutBuf<'_, '_, Block<Self>>) {
+        let count = blocks.len();
+        let (input, output) = blocks.into_raw();
+        unsafe { self.proc_raw(input, output, count) }
+    }
+}
+
+impl<Size: KeySize> CbcDecrypt<'_, '_, Size> {
+    unsafe fn proc_raw(
+        &mut self,
+        input: *const Block<Self>,
+        output: *mut Block<Self>,
+        count: usize,
+    ) {
+        if count == 0 {
+            return;
+        }
+        self.0.prepare(Mode::Decrypt);
+        // processing in place overwrites the last ciphertext block
+        let iv = *input.add(count - 1);
+        self.0.aes.process_blocks(input, output, count);
+        self.0.iv = iv;
+    }
+}
+
+impl<Size: KeySize> BlockEncryptMut for AesCbc<'_, Size> {
+    fn encrypt_with_backend_mut(&mut self, f: impl cipher::BlockClosure<BlockSize = U16>) {
+        f.call(&mut CbcEncrypt(self))
+    }
+}
+
+impl<Size: KeySize> BlockDecryptMut for AesCbc<'_, Size> {
+    fn decrypt_with_backend_mut(&mut self, f: impl cipher::BlockClosure<BlockSize = U16>) {
+        f.call(&mut CbcDecrypt(self))
+    }
+}
+
+/// AES in CTR mode (128 bit big endian counter), chained by the hardware.
+///
+/// The hardware only increments the last 16 bits of the counter block,
+/// carries into the rest are done in software.
+pub struct AesCtr<'a, Size: KeySize> {
+    aes: Aes<'a, Size>,
+    counter: Block<Aes<'a, Size>>,
+    keystream: Block<Aes<'a, Size>>,
+    // position of unused keystream, 16 if none
+    position: usize,
+    configured: bool,
+}
+
+impl<'a, Size: KeySize> AesCtr<'a, Size> {
+    pub fn new(
+        hashcrypt: &'a mut Hashcrypt<Enabled>,
+        key: Key<Size>,
+        iv: &GenericArray<u8, U16>,
+    ) -> Self {
+        Self {
+            aes: Aes {
+                inner: hashcrypt,
+                key,
+            },
+            counter: *iv,
+            keystream: Default::default(),
+            position: 16,
+            configured: false,
+        }
+    }
+
+    pub fn release(self) -> &'a mut Hashcrypt<Enabled> {
+        self.aes.inner
+    }
+
+    fn prepare(&mut self) {
+        if !self.configured || !self.aes.is_configured(Mode::Encrypt, Chaining::Ctr) {
+            self.aes
+                .configure(Mode::Encrypt, Chaining::Ctr, Some(&self.counter));
+            self.configured = true;
+        }
+    }
+
+    // blocks left until the hardware counter wraps
+    fn blocks_until_wrap(&self) -> usize {
+        0x1_0000 - u16::from_be_bytes([self.counter[14], self.counter[15]]) as usize
+    }
+
+    fn advance(&mut self, blocks: usize) {
+        let counter = u128::from_be_bytes(self.counter.as_slice().try_into().unwrap());
+        let counter = counter.wrapping_add(blocks as u128);
+        self.counter.copy_from_slice(&counter.to_be_bytes());
+        // the hardware counter wrapped, without carry
+        if self.counter[14..] == [0, 0] {
+            self.configured = false;
+        }
+    }
+}
+
+impl<Size: KeySize> StreamCipher for AesCtr<'_, Size> {
+    fn try_apply_keystream_inout(
+        &mut self,
+        buf: InOutBuf<'_, '_, u8>,
+    ) -> core::result::Result<(), StreamCipherError> {
+        let len = buf.len();
+        let (input, output) = buf.into_raw();
+        let mut i = 0;
+        while i < len {
+            if self.position == 16 && len - i >= 16 {
+                // whole blocks, as many as possible in one go
+                let blocks = min((len - i) / 16, self.blocks_until_wrap());
+                self.prepare();
+                unsafe {
+                    self.aes.process_blocks(
+                        input.add(i) as *const Block<Aes<'_, Size>>,
+                        output.add(i) as *mut Block<Aes<'_, Size>>,
+                        blocks,
+                    )
+                };
+                self.advance(blocks);
+                i += 16 * blocks;
+                continue;
+            }
+            if self.position == 16 {
+                self.prepare();
+                self.keystream = self.aes.process(&Default::default());
+                self.advance(1);
+                self.position = 0;
+            }
+            unsafe { *output.add(i) = *input.add(i) ^ self.keystream[self.position] };
+            self.position += 1;
+            i += 1;
+        }
+        Ok(())
+    }
+}
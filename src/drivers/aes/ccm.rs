@@ -0,0 +1,304 @@
+//! AES-CCM (NIST SP 800-38C, RFC 3610).
+//!
+//! Tag size (4 to 16 bytes, even) and nonce size (7 to 13 bytes) are type
+//! parameters; the nonce size determines the maximum message length.
+
+use core::marker::PhantomData;
+
+use aead::{AeadCore, AeadInPlace, Error, Nonce, Tag};
+use cipher::{BlockEncrypt, BlockSizeUser};
+
+use super::{Aes, PAR_BLOCKS};
+use crate::traits::{
+    cipher::Block,
+    digest::generic_array::typenum::{U0, U16},
+};
+
+// prevent implementing the size traits for invalid sizes
+mod sealed {
+    use crate::traits::digest::generic_array::{
+        typenum::{U10, U11, U12, U13, U14, U16, U4, U6, U7, U8, U9},
+        ArrayLength,
+    };
+
+    pub trait TagSize: ArrayLength<u8> {}
+
+    impl TagSize for U4 {}
+    impl TagSize for U6 {}
+    impl TagSize for U8 {}
+    impl TagSize for U10 {}
+    impl TagSize for U12 {}
+    impl TagSize for U14 {}
+    impl TagSize for U16 {}
+
+    pub trait NonceSize: ArrayLength<u8> {}
+
+    impl NonceSize for U7 {}
+    impl NonceSize for U8 {}
+    impl NonceSize for U9 {}
+    impl NonceSize for U10 {}
+    impl NonceSize for U11 {}
+    impl NonceSize for U12 {}
+    impl NonceSize for U13 {}
+}
+
+use sealed::{NonceSize, TagSize};
+
+/// AES-CCM using the HASHCRYPT peripheral, with user or PUF key.
+pub type AesCcm<'a, Size, TagSize, NonceSize> = Ccm<Aes<'a, Size>, TagSize, NonceSize>;
+
+pub struct Ccm<B, T: TagSize, N: NonceSize> {
+    cipher: B,
+    _sizes: PhantomData<(T, N)>,
+}
+
+impl<B, T: TagSize, N: NonceSize> Ccm<B, T, N>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    // size of the length (and counter) field
+    const L: usize = 15 - N::USIZE;
+
+    pub fn new(cipher: B) -> Self {
+        Ccm {
+            cipher,
+            _sizes: PhantomData,
+        }
+    }
+
+    pub fn release(self) -> B {
+        self.cipher
+    }
+
+    // formatting of the counter blocks
+    fn counter_block(nonce: &Nonce<Self>, counter: u64) -> Block<B> {
+        let mut block = Block::<B>::default();
+        block[0] = (Self::L - 1) as u8;
+        block[1..16 - Self::L].copy_from_slice(nonce);
+        block[16 - Self::L..].copy_from_slice(&counter.to_be_bytes()[8 - Self::L..]);
+        block
+    }
+
+    // XOR `buffer` with the keystream of counter blocks 1, 2, ...
+    fn apply_keystream(&self, nonce: &Nonce<Self>, buffer: &mut [u8]) {
+        let mut counter = 0;
+        let mut blocks: [Block<B>; PAR_BLOCKS] = Default::default();
+        for chunk in buffer.chunks_mut(16 * PAR_BLOCKS) {
+            let n = chunk.len().div_ceil(16);
+            for block in blocks[..n].iter_mut() {
+                counter += 1;
+                *block = Self::counter_block(nonce, counter);
+            }
+            self.cipher.encrypt_blocks(&mut blocks[..n]);
+            for (byte, key) in chunk.iter_mut().zip(blocks.iter().flatten()) {
+                *byte ^= key;
+            }
+        }
+    }
+
+    fn tag(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        plaintext: &[u8],
+    ) -> aead::Result<Tag<Self>> {
+        let len = plaintext.len() as u64;
+        if Self::L < 8 && len >> (8 * Self::L) != 0 {
+            return Err(Error);
+        }
+
+        let mut b0 = Block::<B>::default();
+        b0[0] = ((!associated_data.is_empty() as usize) << 6
+            | ((T::USIZE - 2) / 2) << 3
+            | (Self::L - 1)) as u8;
+        b0[1..16 - Self::L].copy_from_slice(nonce);
+        b0[16 - Self::L..].copy_from_slice(&len.to_be_bytes()[8 - Self::L..]);
+
+        let mut mac = CbcMac::new(&self.cipher);
+        mac.update(&b0);
+        if !associated_data.is_empty() {
+            let len = associated_data.len() as u64;
+            if len < 0xff00 {
+                mac.update(&(len as u16).to_be_bytes());
+            } else if len <= u32::MAX as u64 {
+                mac.update(&[0xff, 0xfe]);
+                mac.update(&(len as u32).to_be_bytes());
+            } else {
+                mac.update(&[0xff, 0xff]);
+                mac.update(&len.to_be_bytes());
+            }
+            mac.update(associated_data);
+            mac.pad();
+        }
+        mac.update(plaintext);
+        mac.pad();
+
+        let mut s0 = Self::counter_block(nonce, 0);
+        self.cipher.encrypt_block(&mut s0);
+        let mut tag = Tag::<Self>::default();
+        for (i, byte) in tag.iter_mut().enumerate() {
+            *byte = mac.x[i] ^ s0[i];
+        }
+        Ok(tag)
+    }
+}
+
+impl<B, T: TagSize, N: NonceSize> AeadCore for Ccm<B, T, N> {
+    type NonceSize = N;
+    type TagSize = T;
+    type CiphertextOverhead = U0;
+}
+
+impl<B, T: TagSize, N: NonceSize> AeadInPlace for Ccm<B, T, N>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> aead::Result<Tag<Self>> {
+        let tag = self.tag(nonce, associated_data, buffer)?;
+        self.apply_keystream(nonce, buffer);
+        Ok(tag)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> aead::Result<()> {
+        self.apply_keystream(nonce, buffer);
+        let expected = match self.tag(nonce, associated_data, buffer) {
+            Ok(expected) => expected,
+            Err(error) => {
+                self.apply_keystream(nonce, buffer);
+                return Err(error);
+            }
+        };
+        // constant time comparison
+        let difference = expected
+            .iter()
+            .zip(tag.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference != 0 {
+            // do not release unauthenticated plaintext
+            self.apply_keystream(nonce, buffer);
+            return Err(Error);
+        }
+        Ok(())
+    }
+}
+
+struct CbcMac<'a, B: BlockEncrypt + BlockSizeUser<BlockSize = U16>> {
+    cipher: &'a B,
+    x: Block<B>,
+    position: usize,
+}
+
+impl<'a, B: BlockEncrypt + BlockSizeUser<BlockSize = U16>> CbcMac<'a, B> {
+    fn new(cipher: &'a B) -> Self {
+        CbcMac {
+            cipher,
+            x: Default::default(),
+            position: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.x[self.position] ^= byte;
+            self.position += 1;
+            if self.position == 16 {
+                self.cipher.encrypt_block(&mut self.x);
+                self.position = 0;
+            }
+        }
+    }
+
+    // zero-pad to a block boundary
+    fn pad(&mut self) {
+        if self.position != 0 {
+            self.cipher.encrypt_block(&mut self.x);
+            self.position = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::digest::generic_array::typenum::{U12, U4, U6, U7, U8};
+    use aes::{cipher::KeyInit, Aes128};
+    use hex_literal::hex;
+
+    const KEY: [u8; 16] = hex!("404142434445464748494a4b4c4d4e4f");
+
+    fn check<T: TagSize, N: NonceSize>(
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        ciphertext: &[u8],
+        tag: &[u8],
+    ) {
+        let ccm = Ccm::<_, T, N>::new(Aes128::new_from_slice(&KEY).unwrap());
+        let nonce = Nonce::<Ccm<Aes128, T, N>>::from_slice(nonce);
+
+        let mut buffer = [0u8; 64];
+        let buffer = &mut buffer[..plaintext.len()];
+        buffer.copy_from_slice(plaintext);
+        let computed = ccm.encrypt_in_place_detached(nonce, aad, buffer).unwrap();
+        assert_eq!(buffer, ciphertext);
+        assert_eq!(computed.as_slice(), tag);
+
+        ccm.decrypt_in_place_detached(nonce, aad, buffer, &computed)
+            .unwrap();
+        assert_eq!(buffer, plaintext);
+
+        let mut forged = computed;
+        forged[0] ^= 1;
+        buffer.copy_from_slice(ciphertext);
+        assert!(ccm
+            .decrypt_in_place_detached(nonce, aad, buffer, &forged)
+            .is_err());
+        // ciphertext is restored
+        assert_eq!(buffer, ciphertext);
+    }
+
+    // examples of NIST SP 800-38C, appendix C
+    #[test]
+    fn example_1() {
+        check::<U4, U7>(
+            &hex!("10111213141516"),
+            &hex!("0001020304050607"),
+            &hex!("20212223"),
+            &hex!("7162015b"),
+            &hex!("4dac255d"),
+        );
+    }
+
+    #[test]
+    fn example_2() {
+        check::<U6, U8>(
+            &hex!("1011121314151617"),
+            &hex!("000102030405060708090a0b0c0d0e0f"),
+            &hex!("202122232425262728292a2b2c2d2e2f"),
+            &hex!("d2a1f0e051ea5f62081a7792073d593d"),
+            &hex!("1fc64fbfaccd"),
+        );
+    }
+
+    #[test]
+    fn example_3() {
+        check::<U8, U12>(
+            &hex!("101112131415161718191a1b"),
+            &hex!("000102030405060708090a0b0c0d0e0f10111213"),
+            &hex!("202122232425262728292a2b2c2d2e2f3031323334353637"),
+            &hex!("e3b201a9f5b71a7a9b1ceaeccd97e70b6176aad9a4428aa5"),
+            &hex!("484392fbc1b09951"),
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! AES-CMAC (NIST SP 800-38B, RFC 4493).
+
+use cipher::{BlockEncrypt, BlockSizeUser};
+use digest::{FixedOutput, FixedOutputReset, MacMarker, Output, OutputSizeUser, Reset, Update};
+
+use super::Aes;
+use crate::traits::{
+    cipher::Block,
+    digest::generic_array::{typenum::U16, GenericArray},
+};
+
+/// AES-CMAC using the HASHCRYPT peripheral, with user or PUF key.
+pub type AesCmac<'a, Size> = Cmac<Aes<'a, Size>>;
+
+pub struct Cmac<B: BlockEncrypt + BlockSizeUser<BlockSize = U16>> {
+    cipher: B,
+    // subkeys for a complete and a padded last block
+    k1: Block<B>,
+    k2: Block<B>,
+    x: Block<B>,
+    // the last block is only processed on finalization
+    buffer: Block<B>,
+    position: usize,
+}
+
+impl<B> Cmac<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    pub fn new(cipher: B) -> Self {
+        let mut l = Block::<B>::default();
+        cipher.encrypt_block(&mut l);
+        let k1 = double(&l);
+        let k2 = double(&k1);
+        Cmac {
+            cipher,
+            k1,
+            k2,
+            x: Default::default(),
+            buffer: Default::default(),
+            position: 0,
+        }
+    }
+
+    pub fn release(self) -> B {
+        self.cipher
+    }
+
+    fn finish(&mut self) -> Block<B> {
+        let subkey = if self.position == 16 {
+            &self.k1
+        } else {
+            self.buffer[self.position] = 0x80;
+            for byte in self.buffer[self.position + 1..].iter_mut() {
+                *byte = 0;
+            }
+            &self.k2
+        };
+        let mut mac = self.x;
+        for ((byte, data), key) in mac.iter_mut().zip(self.buffer.iter()).zip(subkey.iter()) {
+            *byte ^= data ^ key;
+        }
+        self.cipher.encrypt_block(&mut mac);
+        mac
+    }
+}
+
+// multiplication by x in GF(2^128)
+fn double(block: &GenericArray<u8, U16>) -> GenericArray<u8, U16> {
+    let mut value = [0u8; 16];
+    value.copy_from_slice(block);
+    let value = u128::from_be_bytes(value);
+    let doubled = (value << 1) ^ (0x87 & 0u128.wrapping_sub(value >> 127));
+    doubled.to_be_bytes().into()
+}
+
+impl<B> OutputSizeUser for Cmac<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    type OutputSize = U16;
+}
+
+impl<B> MacMarker for Cmac<B> where B: BlockEncrypt + BlockSizeUser<BlockSize = U16> {}
+
+impl<B> Update for Cmac<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            if self.position == 16 {
+                for (x, byte) in self.x.iter_mut().zip(self.buffer.iter()) {
+                    *x ^= byte;
+                }
+                self.cipher.encrypt_block(&mut self.x);
+                self.position = 0;
+            }
+            self.buffer[self.position] = *byte;
+            self.position += 1;
+        }
+    }
+}
+
+impl<B> FixedOutput for Cmac<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    fn finalize_into(mut self, out: &mut Output<Self>) {
+        out.copy_from_slice(&self.finish());
+    }
+}
+
+impl<B> Reset for Cmac<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    fn reset(&mut self) {
+        self.x = Default::default();
+        self.position = 0;
+    }
+}
+
+impl<B> FixedOutputReset for Cmac<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        out.copy_from_slice(&self.finish());
+        Reset::reset(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::{cipher::KeyInit, Aes128};
+    use digest::Mac;
+    use hex_literal::hex;
+
+    const KEY: [u8; 16] = hex!("2b7e151628aed2a6abf7158809cf4f3c");
+    const MESSAGE: [u8; 64] = hex!(
+        "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51"
+        "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+    );
+
+    // examples of RFC 4493, section 4
+    #[test]
+    fn rfc4493() {
+        let cases: [(usize, [u8; 16]); 4] = [
+            (0, hex!("bb1d6929e95937287fa37d129b756746")),
+            (16, hex!("070a16b46b4d4144f79bdd9dd04a287c")),
+            (40, hex!("dfa66747de9ae63030ca32611497c827")),
+            (64, hex!("51f0bebf7e3b9d92fc49741779363cfe")),
+        ];
+        let mut cmac = Cmac::new(Aes128::new(&KEY.into()));
+        for (len, expected) in cases.iter() {
+            // byte-wise, to exercise the buffering
+            for byte in &MESSAGE[..*len] {
+                Mac::update(&mut cmac, core::slice::from_ref(byte));
+            }
+            assert_eq!(cmac.finalize_fixed_reset().as_slice(), expected);
+
+            Mac::update(&mut cmac, &MESSAGE[..*len]);
+            assert!(cmac.verify_slice_reset(expected).is_ok());
+        }
+    }
+}
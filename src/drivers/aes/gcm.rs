@@ -0,0 +1,257 @@
+//! AES-GCM (NIST SP 800-38D), with 96 bit nonces and 128 bit tags.
+//!
+//! The block cipher runs in hardware, GHASH is computed in software.
+
+use core::convert::TryInto;
+
+use aead::{AeadCore, AeadInPlace, Error, Nonce, Tag};
+use cipher::{BlockEncrypt, BlockSizeUser};
+
+use super::{Aes, PAR_BLOCKS};
+use crate::traits::{
+    cipher::Block,
+    digest::generic_array::typenum::{U0, U12, U16},
+};
+
+/// AES-GCM using the HASHCRYPT peripheral, with user or PUF key.
+pub type AesGcm<'a, Size> = Gcm<Aes<'a, Size>>;
+
+// limit on the plaintext length of the standard, 2^39 - 256 bits
+const MAX_LEN: u64 = (1 << 36) - 32;
+
+// the reduction polynomial, in GCM's reflected bit order
+const R: u128 = 0xe1 << 120;
+
+pub struct Gcm<B> {
+    cipher: B,
+    // the hash key, E(0)
+    h: u128,
+}
+
+impl<B> Gcm<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    pub fn new(cipher: B) -> Self {
+        let mut h = Block::<B>::default();
+        cipher.encrypt_block(&mut h);
+        Gcm {
+            h: u128::from_be_bytes(h.into()),
+            cipher,
+        }
+    }
+
+    pub fn release(self) -> B {
+        self.cipher
+    }
+
+    // XOR `buffer` with the keystream following the pre-counter block `j0`
+    fn apply_keystream(&self, j0: u128, buffer: &mut [u8]) {
+        let mut counter = j0;
+        let mut blocks: [Block<B>; PAR_BLOCKS] = Default::default();
+        for chunk in buffer.chunks_mut(16 * PAR_BLOCKS) {
+            let n = chunk.len().div_ceil(16);
+            for block in blocks[..n].iter_mut() {
+                counter = inc32(counter);
+                block.copy_from_slice(&counter.to_be_bytes());
+            }
+            self.cipher.encrypt_blocks(&mut blocks[..n]);
+            for (byte, key) in chunk.iter_mut().zip(blocks.iter().flatten()) {
+                *byte ^= key;
+            }
+        }
+    }
+
+    fn ghash(&self, associated_data: &[u8], ciphertext: &[u8]) -> u128 {
+        let mut y = 0;
+        for data in &[associated_data, ciphertext] {
+            for chunk in data.chunks(16) {
+                let mut block = [0u8; 16];
+                block[..chunk.len()].copy_from_slice(chunk);
+                y = mul(y ^ u128::from_be_bytes(block), self.h);
+            }
+        }
+        let lengths = ((associated_data.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        mul(y ^ lengths, self.h)
+    }
+
+    fn tag(&self, j0: u128, associated_data: &[u8], ciphertext: &[u8]) -> Tag<Self> {
+        let mut mask = Block::<B>::from(j0.to_be_bytes());
+        self.cipher.encrypt_block(&mut mask);
+        let tag = self.ghash(associated_data, ciphertext) ^ u128::from_be_bytes(mask.into());
+        tag.to_be_bytes().into()
+    }
+
+    fn j0(nonce: &Nonce<Self>) -> u128 {
+        let mut j0 = [0u8; 16];
+        j0[..12].copy_from_slice(nonce);
+        j0[15] = 1;
+        u128::from_be_bytes(j0)
+    }
+}
+
+impl<B> AeadCore for Gcm<B> {
+    type NonceSize = U12;
+    type TagSize = U16;
+    type CiphertextOverhead = U0;
+}
+
+impl<B> AeadInPlace for Gcm<B>
+where
+    B: BlockEncrypt + BlockSizeUser<BlockSize = U16>,
+{
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> aead::Result<Tag<Self>> {
+        if buffer.len() as u64 > MAX_LEN {
+            return Err(Error);
+        }
+        let j0 = Self::j0(nonce);
+        self.apply_keystream(j0, buffer);
+        Ok(self.tag(j0, associated_data, buffer))
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> aead::Result<()> {
+        if buffer.len() as u64 > MAX_LEN {
+            return Err(Error);
+        }
+        let j0 = Self::j0(nonce);
+        let expected = self.tag(j0, associated_data, buffer);
+        // constant time comparison
+        let difference = expected
+            .iter()
+            .zip(tag.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference != 0 {
+            return Err(Error);
+        }
+        self.apply_keystream(j0, buffer);
+        Ok(())
+    }
+}
+
+// increment the last 32 bits
+fn inc32(counter: u128) -> u128 {
+    let low: u32 = (counter & 0xffff_ffff).try_into().unwrap();
+    (counter & !0xffff_ffff) | low.wrapping_add(1) as u128
+}
+
+// multiplication in GF(2^128), in constant time
+fn mul(x: u128, y: u128) -> u128 {
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        z ^= v & 0u128.wrapping_sub((x >> i) & 1);
+        v = (v >> 1) ^ (R & 0u128.wrapping_sub(v & 1));
+    }
+    z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::{cipher::KeyInit, Aes128};
+    use hex_literal::hex;
+
+    fn check(
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        ciphertext: &[u8],
+        tag: &[u8],
+    ) {
+        let gcm = Gcm::new(Aes128::new_from_slice(key).unwrap());
+        let nonce = Nonce::<Gcm<Aes128>>::from_slice(nonce);
+
+        let mut buffer = [0u8; 64];
+        let buffer = &mut buffer[..plaintext.len()];
+        buffer.copy_from_slice(plaintext);
+        let computed = gcm.encrypt_in_place_detached(nonce, aad, buffer).unwrap();
+        assert_eq!(buffer, ciphertext);
+        assert_eq!(computed.as_slice(), tag);
+
+        gcm.decrypt_in_place_detached(nonce, aad, buffer, &computed)
+            .unwrap();
+        assert_eq!(buffer, plaintext);
+
+        let mut forged = computed;
+        forged[0] ^= 1;
+        buffer.copy_from_slice(ciphertext);
+        assert!(gcm
+            .decrypt_in_place_detached(nonce, aad, buffer, &forged)
+            .is_err());
+        // buffer is left untouched
+        assert_eq!(buffer, ciphertext);
+    }
+
+    // test cases 1 to 4 of the GCM specification, as used by NIST's CAVP
+    #[test]
+    fn empty() {
+        check(
+            &[0; 16],
+            &[0; 12],
+            &[],
+            &[],
+            &[],
+            &hex!("58e2fccefa7e3061367f1d57a4e7455a"),
+        );
+    }
+
+    #[test]
+    fn one_block() {
+        check(
+            &[0; 16],
+            &[0; 12],
+            &[],
+            &[0; 16],
+            &hex!("0388dace60b6a392f328c2b971b2fe78"),
+            &hex!("ab6e47d42cec13bdf53a67b21257bddf"),
+        );
+    }
+
+    #[test]
+    fn four_blocks() {
+        check(
+            &hex!("feffe9928665731c6d6a8f9467308308"),
+            &hex!("cafebabefacedbaddecaf888"),
+            &[],
+            &hex!(
+                "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72"
+                "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255"
+            ),
+            &hex!(
+                "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e"
+                "21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091473f5985"
+            ),
+            &hex!("4d5c2af327cd64a62cf35abd2ba6fab4"),
+        );
+    }
+
+    #[test]
+    fn partial_block_and_associated_data() {
+        check(
+            &hex!("feffe9928665731c6d6a8f9467308308"),
+            &hex!("cafebabefacedbaddecaf888"),
+            &hex!("feedfacedeadbeeffeedfacedeadbeefabaddad2"),
+            &hex!(
+                "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72"
+                "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39"
+            ),
+            &hex!(
+                "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e"
+                "21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091"
+            ),
+            &hex!("5bc94fbc3221a5db94fae95ae7121a47"),
+        );
+    }
+}
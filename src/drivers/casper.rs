@@ -0,0 +1,641 @@
+//! Big-number and modular arithmetic using the CASPER accelerator.
+//!
+//! Numbers are slices of 64-bit limbs, least significant limb first. They
+//! are copied into the RAM of the accelerator, which computes products,
+//! sums and differences of whole numbers; the control flow of the Montgomery
+//! multiplication and exponentiation runs on the CPU.
+//!
+//! The arithmetic is generic over an `Engine`; `SoftEngine` implements it
+//! in software, as reference and for testing.
+
+use core::cmp::Ordering;
+
+use crate::{
+    peripherals::casper::{Casper, Operations},
+    typestates::init_state::Enabled,
+};
+
+pub mod p256;
+pub use p256::CasperP256;
+
+pub mod rsa;
+pub use rsa::{CasperRsa, RsaPublicKey};
+
+// the two interleaved banks of CASPER RAM (SRAMX), 4K each;
+// a 64-bit word has its lower half in the first, its upper half in the second bank
+const RAM_LOW: usize = 0x1400_0000;
+const RAM_HIGH: usize = 0x1400_4000;
+const RAM_WORDS: usize = 0x400;
+
+// limit of CTRL1.ITER, and hence of the operand length
+const MAX_LEN: usize = 0x100;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Operands and result have inconsistent lengths, or are empty
+    Length,
+    /// Operands are longer than 256 words, or do not fit in the memory of the engine
+    TooLarge,
+    /// Montgomery arithmetic requires an odd modulus
+    EvenModulus,
+    /// Operands of modular arithmetic must be reduced, i.e. less than the modulus
+    NotReduced,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Operations on 64-bit words in the memory of an arithmetic engine.
+///
+/// Offsets and lengths are in words, lengths are at most 256.
+pub trait Engine {
+    /// Size of the memory, in words
+    const WORDS: usize;
+
+    fn read(&self, offset: usize) -> u64;
+    fn write(&mut self, offset: usize, value: u64);
+
+    /// `r[..=n] = r[..n] + a[..n] * b`, with `b` the single word at offset `b`.
+    fn mul_add(&mut self, r: usize, a: usize, b: usize, n: usize);
+    /// `r[..n] += a[..n]`, returning the carry.
+    fn add(&mut self, r: usize, a: usize, n: usize) -> bool;
+    /// `r[..n] -= a[..n]`, returning the borrow.
+    fn sub(&mut self, r: usize, a: usize, n: usize) -> bool;
+}
+
+/// The CASPER accelerator.
+pub struct CasperEngine {
+    inner: Casper<Enabled>,
+}
+
+impl CasperEngine {
+    pub fn new(casper: Casper<Enabled>) -> Self {
+        Self { inner: casper }
+    }
+
+    pub fn release(self) -> Casper<Enabled> {
+        self.inner
+    }
+
+    // returns STATUS.CARRY; offsets of CTRL0 and CTRL1 are in bytes
+    fn run(&mut self, operation: Operations, ab: usize, cd: usize, res: usize, n: usize) -> bool {
+        assert!(n > 0 && n <= MAX_LEN);
+        let (ab, cd, res) = (8 * ab as u32, 8 * cd as u32, 8 * res as u32);
+        self.inner
+            .raw
+            .ctrl0
+            .write(|w| unsafe { w.bits(ab | cd << 16) });
+        // writing CTRL1 starts the operation
+        self.inner
+            .raw
+            .ctrl1
+            .write(|w| unsafe { w.bits((n as u32 - 1) | (operation as u32) << 8 | res << 16) });
+        while self.inner.raw.status.read().done().bit_is_clear() {
+            continue;
+        }
+        self.inner.raw.status.read().carry().bit_is_set()
+    }
+}
+
+impl Engine for CasperEngine {
+    const WORDS: usize = RAM_WORDS;
+
+    fn read(&self, offset: usize) -> u64 {
+        assert!(offset < Self::WORDS);
+        let (low, high) = unsafe {
+            (
+                core::ptr::read_volatile((RAM_LOW + 4 * offset) as *const u32),
+                core::ptr::read_volatile((RAM_HIGH + 4 * offset) as *const u32),
+            )
+        };
+        (high as u64) << 32 | low as u64
+    }
+
+    fn write(&mut self, offset: usize, value: u64) {
+        assert!(offset < Self::WORDS);
+        unsafe {
+            core::ptr::write_volatile((RAM_LOW + 4 * offset) as *mut u32, value as u32);
+            core::ptr::write_volatile((RAM_HIGH + 4 * offset) as *mut u32, (value >> 32) as u32);
+        }
+    }
+
+    // cf `MultprecMultiply` in `casper.c` of the SDK
+    fn mul_add(&mut self, r: usize, a: usize, b: usize, n: usize) {
+        self.run(Operations::Mul6464Sum, b, a, r, n);
+    }
+
+    fn add(&mut self, r: usize, a: usize, n: usize) -> bool {
+        self.run(Operations::Add64, a, 0, r, n)
+    }
+
+    fn sub(&mut self, r: usize, a: usize, n: usize) -> bool {
+        // Sub64 leaves the final borrow implicit
+        let borrow = compare(self, r, a, n) == Ordering::Less;
+        self.run(Operations::Sub64, a, 0, r, n);
+        borrow
+    }
+}
+
+// `r[..n] += 1`, returning the carry
+fn increment<E: Engine + ?Sized>(engine: &mut E, r: usize, n: usize) -> bool {
+    for i in r..r + n {
+        let value = engine.read(i).wrapping_add(1);
+        engine.write(i, value);
+        if value != 0 {
+            return false;
+        }
+    }
+    true
+}
+
+fn compare<E: Engine + ?Sized>(engine: &E, a: usize, b: usize, n: usize) -> Ordering {
+    for i in (0..n).rev() {
+        match engine.read(a + i).cmp(&engine.read(b + i)) {
+            Ordering::Equal => continue,
+            ordering => return ordering,
+        }
+    }
+    Ordering::Equal
+}
+
+/// Software implementation of `Engine`, e.g. to test against.
+pub struct SoftEngine {
+    memory: [u64; RAM_WORDS],
+}
+
+impl SoftEngine {
+    pub fn new() -> Self {
+        Self {
+            memory: [0; RAM_WORDS],
+        }
+    }
+}
+
+impl Default for SoftEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine for SoftEngine {
+    const WORDS: usize = RAM_WORDS;
+
+    fn read(&self, offset: usize) -> u64 {
+        self.memory[offset]
+    }
+
+    fn write(&mut self, offset: usize, value: u64) {
+        self.memory[offset] = value;
+    }
+
+    fn mul_add(&mut self, r: usize, a: usize, b: usize, n: usize) {
+        let b = self.memory[b] as u128;
+        let mut carry = 0u128;
+        for i in 0..n {
+            let value = self.memory[r + i] as u128 + self.memory[a + i] as u128 * b + carry;
+            self.memory[r + i] = value as u64;
+            carry = value >> 64;
+        }
+        self.memory[r + n] = carry as u64;
+    }
+
+    fn add(&mut self, r: usize, a: usize, n: usize) -> bool {
+        let mut carry = false;
+        for i in 0..n {
+            let (sum, overflow1) = self.memory[r + i].overflowing_add(self.memory[a + i]);
+            let (sum, overflow2) = sum.overflowing_add(carry as u64);
+            self.memory[r + i] = sum;
+            carry = overflow1 || overflow2;
+        }
+        carry
+    }
+
+    fn sub(&mut self, r: usize, a: usize, n: usize) -> bool {
+        let mut borrow = false;
+        for i in 0..n {
+            let (difference, underflow1) = self.memory[r + i].overflowing_sub(self.memory[a + i]);
+            let (difference, underflow2) = difference.overflowing_sub(borrow as u64);
+            self.memory[r + i] = difference;
+            borrow = underflow1 || underflow2;
+        }
+        borrow
+    }
+}
+
+/// Big-number arithmetic on top of an `Engine`.
+pub struct Arithmetic<E: Engine> {
+    engine: E,
+}
+
+/// Big-number arithmetic using the CASPER accelerator.
+pub type CasperArithmetic = Arithmetic<CasperEngine>;
+
+impl CasperArithmetic {
+    pub fn from_casper(casper: Casper<Enabled>) -> Self {
+        Self::new(CasperEngine::new(casper))
+    }
+}
+
+// modulus and Montgomery constant, loaded in engine memory
+struct Modulus {
+    offset: usize,
+    len: usize,
+    // -N^-1 mod 2^64
+    inverse: u64,
+}
+
+impl<E: Engine> Arithmetic<E> {
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    pub fn release(self) -> E {
+        self.engine
+    }
+
+    /// `r = a + b`, returning the carry; all of the same length.
+    pub fn add(&mut self, a: &[u64], b: &[u64], r: &mut [u64]) -> Result<bool> {
+        let n = a.len();
+        if b.len() != n || r.len() != n {
+            return Err(Error::Length);
+        }
+        Self::check_len(n)?;
+        Self::check_size(2 * n)?;
+        self.load(0, a);
+        self.load(n, b);
+        let carry = self.engine.add(0, n, n);
+        self.store(0, r);
+        Ok(carry)
+    }
+
+    /// `r = a - b`, returning the borrow; all of the same length.
+    pub fn sub(&mut self, a: &[u64], b: &[u64], r: &mut [u64]) -> Result<bool> {
+        let n = a.len();
+        if b.len() != n || r.len() != n {
+            return Err(Error::Length);
+        }
+        Self::check_len(n)?;
+        Self::check_size(2 * n)?;
+        self.load(0, a);
+        self.load(n, b);
+        let borrow = self.engine.sub(0, n, n);
+        self.store(0, r);
+        Ok(borrow)
+    }
+
+    /// `r = a * b`, where `r` has the length of `a` and `b` combined.
+    pub fn mul(&mut self, a: &[u64], b: &[u64], r: &mut [u64]) -> Result<()> {
+        let (m, n) = (a.len(), b.len());
+        if r.len() != m + n {
+            return Err(Error::Length);
+        }
+        Self::check_len(m)?;
+        Self::check_len(n)?;
+        Self::check_size(2 * (m + n))?;
+        self.load(0, a);
+        self.load(m, b);
+        self.mul_at(m + n, 0, m, m, n);
+        self.store(m + n, r);
+        Ok(())
+    }
+
+    /// Montgomery multiplication `r = a * b / R mod modulus`, with `R = 2^(64 n)`,
+    /// `n` the length of the modulus.
+    ///
+    /// The modulus must be odd; all operands have its length and are reduced.
+    pub fn mont_mul(&mut self, a: &[u64], b: &[u64], modulus: &[u64], r: &mut [u64]) -> Result<()> {
+        let n = modulus.len();
+        if a.len() != n || b.len() != n || r.len() != n {
+            return Err(Error::Length);
+        }
+        let modulus = self.load_modulus(modulus, 6 * n + 3)?;
+        self.load_reduced(n, a, &modulus)?;
+        self.load_reduced(2 * n, b, &modulus)?;
+        self.mont_mul_at(2 * n, n, 2 * n, &modulus, 3 * n);
+        self.store(2 * n, r);
+        Ok(())
+    }
+
+    /// Modular exponentiation `r = base^exponent mod modulus`.
+    ///
+    /// The modulus must be odd; base and result have its length, and the base
+    /// is reduced. The exponent may have any length.
+    ///
+    /// The computation is not constant time, use it with public exponents only.
+    pub fn mod_exp(
+        &mut self,
+        base: &[u64],
+        exponent: &[u64],
+        modulus: &[u64],
+        r: &mut [u64],
+    ) -> Result<()> {
+        let n = modulus.len();
+        if base.len() != n || r.len() != n {
+            return Err(Error::Length);
+        }
+        // modulus, base, accumulator, R^2, one, scratch
+        let modulus = self.load_modulus(modulus, 8 * n + 3)?;
+        let (base_offset, acc, rr, one, scratch) = (n, 2 * n, 3 * n, 4 * n, 5 * n);
+        self.load_reduced(base_offset, base, &modulus)?;
+
+        // R^2 mod N, doubling 1 mod N
+        self.zero(rr, n);
+        let trivial = n == 1 && self.engine.read(modulus.offset) == 1;
+        if !trivial {
+            self.engine.write(rr, 1);
+        }
+        for _ in 0..128 * n {
+            self.copy(scratch, rr, n);
+            let carry = self.engine.add(rr, scratch, n);
+            if carry || compare(&self.engine, rr, modulus.offset, n) != Ordering::Less {
+                self.engine.sub(rr, modulus.offset, n);
+            }
+        }
+
+        // into the Montgomery domain
+        self.zero(one, n);
+        self.engine.write(one, 1);
+        self.mont_mul_at(acc, one, rr, &modulus, scratch);
+        self.copy(one, base_offset, n);
+        self.mont_mul_at(base_offset, one, rr, &modulus, scratch);
+
+        for word in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                self.mont_mul_at(acc, acc, acc, &modulus, scratch);
+                if word >> bit & 1 != 0 {
+                    self.mont_mul_at(acc, acc, base_offset, &modulus, scratch);
+                }
+            }
+        }
+
+        // and out of it
+        self.zero(one, n);
+        self.engine.write(one, 1);
+        self.mont_mul_at(acc, acc, one, &modulus, scratch);
+        self.store(acc, r);
+        Ok(())
+    }
+
+    fn check_len(len: usize) -> Result<()> {
+        match len {
+            0 => Err(Error::Length),
+            len if len > MAX_LEN => Err(Error::TooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_size(words: usize) -> Result<()> {
+        if words > E::WORDS {
+            return Err(Error::TooLarge);
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, offset: usize, words: &[u64]) {
+        for (i, word) in words.iter().enumerate() {
+            self.engine.write(offset + i, *word);
+        }
+    }
+
+    fn store(&self, offset: usize, words: &mut [u64]) {
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = self.engine.read(offset + i);
+        }
+    }
+
+    fn zero(&mut self, offset: usize, n: usize) {
+        for i in offset..offset + n {
+            self.engine.write(i, 0);
+        }
+    }
+
+    fn copy(&mut self, to: usize, from: usize, n: usize) {
+        for i in 0..n {
+            let word = self.engine.read(from + i);
+            self.engine.write(to + i, word);
+        }
+    }
+
+    // loads the modulus at offset 0, checking that `words` fit in memory
+    fn load_modulus(&mut self, modulus: &[u64], words: usize) -> Result<Modulus> {
+        // REDC adds numbers one word longer than the modulus
+        Self::check_len(modulus.len() + 1)?;
+        if modulus[0] & 1 == 0 {
+            return Err(Error::EvenModulus);
+        }
+        Self::check_size(words)?;
+        self.load(0, modulus);
+        Ok(Modulus {
+            offset: 0,
+            len: modulus.len(),
+            inverse: inverse(modulus[0]).wrapping_neg(),
+        })
+    }
+
+    fn load_reduced(&mut self, offset: usize, words: &[u64], modulus: &Modulus) -> Result<()> {
+        self.load(offset, words);
+        if compare(&self.engine, offset, modulus.offset, modulus.len) != Ordering::Less {
+            return Err(Error::NotReduced);
+        }
+        Ok(())
+    }
+
+    // `r[..m + n] = a[..m] * b[..n]`; `r` must not overlap the operands
+    fn mul_at(&mut self, r: usize, a: usize, m: usize, b: usize, n: usize) {
+        self.zero(r, m + n);
+        for i in 0..n {
+            self.engine.mul_add(r + i, a, b + i, m);
+        }
+    }
+
+    // `r = a * b / R mod N`, using `3 n + 3` words of scratch space;
+    // `r` may alias the operands
+    fn mont_mul_at(&mut self, r: usize, a: usize, b: usize, modulus: &Modulus, scratch: usize) {
+        let n = modulus.len;
+        // product t (2 n + 1 words), multiple u of N (n + 1 words), factor m (1 word)
+        let (t, u, m) = (scratch, scratch + 2 * n + 1, scratch + 3 * n + 2);
+        self.mul_at(t, a, n, b, n);
+        self.engine.write(t + 2 * n, 0);
+
+        // REDC, word by word: add multiples of N to clear the lower half of t
+        for i in 0..n {
+            let factor = self.engine.read(t + i).wrapping_mul(modulus.inverse);
+            self.engine.write(m, factor);
+            self.zero(u, n);
+            self.engine.mul_add(u, modulus.offset, m, n);
+            if self.engine.add(t + i, u, n + 1) {
+                increment(&mut self.engine, t + i + n + 1, n - i);
+            }
+        }
+
+        // t / R < 2 N
+        let upper = t + n;
+        if self.engine.read(upper + n) != 0
+            || compare(&self.engine, upper, modulus.offset, n) != Ordering::Less
+        {
+            self.engine.sub(upper, modulus.offset, n);
+        }
+        self.copy(r, upper, n);
+    }
+}
+
+// inverse modulo 2^64 of an odd number, by Newton iteration
+fn inverse(odd: u64) -> u64 {
+    // correct to 3 bits
+    let mut inverse = odd;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(odd.wrapping_mul(inverse)));
+    }
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use num_bigint::BigUint;
+    use std::{vec, vec::Vec};
+
+    // xorshift, for reproducible operands
+    struct Random(u64);
+
+    impl Random {
+        fn fill(&mut self, words: &mut [u64]) {
+            for word in words.iter_mut() {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                *word = self.0;
+            }
+        }
+    }
+
+    fn big(words: &[u64]) -> BigUint {
+        let digits: Vec<u32> = words
+            .iter()
+            .flat_map(|word| [*word as u32, (word >> 32) as u32])
+            .collect();
+        BigUint::new(digits)
+    }
+
+    fn arithmetic() -> Arithmetic<SoftEngine> {
+        Arithmetic::new(SoftEngine::new())
+    }
+
+    #[test]
+    fn inverse_mod_2_64() {
+        for odd in [1u64, 3, 0xffff_ffff_ffff_ffff, 0x1234_5678_9abc_def1] {
+            assert_eq!(odd.wrapping_mul(inverse(odd)), 1);
+        }
+    }
+
+    #[test]
+    fn add_sub_mul() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        let mut arithmetic = arithmetic();
+        for &(m, n) in &[(1, 1), (4, 4), (8, 3), (32, 32)] {
+            let (mut a, mut b) = (vec![0; m], vec![0; n]);
+            random.fill(&mut a);
+            random.fill(&mut b);
+
+            let mut product = vec![0; m + n];
+            arithmetic.mul(&a, &b, &mut product).unwrap();
+            assert_eq!(big(&product), big(&a) * big(&b));
+
+            if m == n {
+                let modulus = BigUint::from(1u8) << (64 * n);
+                let mut r = vec![0; n];
+                let carry = arithmetic.add(&a, &b, &mut r).unwrap();
+                assert_eq!(big(&r), (big(&a) + big(&b)) % &modulus);
+                assert_eq!(carry, big(&a) + big(&b) >= modulus);
+
+                let borrow = arithmetic.sub(&a, &b, &mut r).unwrap();
+                assert_eq!(big(&r), (big(&a) + &modulus - big(&b)) % &modulus);
+                assert_eq!(borrow, big(&a) < big(&b));
+            }
+        }
+    }
+
+    #[test]
+    fn montgomery() {
+        let mut random = Random(0x9e37_79b9_7f4a_7c15);
+        let mut arithmetic = arithmetic();
+        for &n in &[1, 2, 4, 16] {
+            let (mut a, mut b, mut modulus) = (vec![0; n], vec![0; n], vec![0; n]);
+            random.fill(&mut modulus);
+            modulus[0] |= 1;
+            random.fill(&mut a);
+            random.fill(&mut b);
+            // reduce
+            a[n - 1] %= modulus[n - 1];
+            b[n - 1] %= modulus[n - 1];
+
+            let mut r = vec![0; n];
+            arithmetic.mont_mul(&a, &b, &modulus, &mut r).unwrap();
+            let big_r = BigUint::from(1u8) << (64 * n);
+            let m = big(&modulus);
+            // r R = a b mod N
+            assert_eq!((big(&r) * big_r) % &m, (big(&a) * big(&b)) % &m);
+            assert!(big(&r) < m);
+        }
+    }
+
+    #[test]
+    fn exponentiation() {
+        let mut random = Random(0xdead_beef_cafe_f00d);
+        let mut arithmetic = arithmetic();
+        for &(n, e) in &[(1, 1), (2, 1), (4, 2), (32, 1), (32, 32)] {
+            let (mut base, mut exponent, mut modulus) = (vec![0; n], vec![0; e], vec![0; n]);
+            random.fill(&mut modulus);
+            modulus[0] |= 1;
+            random.fill(&mut base);
+            base[n - 1] %= modulus[n - 1];
+            random.fill(&mut exponent);
+
+            let mut r = vec![0; n];
+            arithmetic
+                .mod_exp(&base, &exponent, &modulus, &mut r)
+                .unwrap();
+            assert_eq!(big(&r), big(&base).modpow(&big(&exponent), &big(&modulus)));
+        }
+
+        // RSA public exponent, and trivial cases
+        let mut r = [0; 2];
+        arithmetic
+            .mod_exp(&[5, 0], &[65537], &[0xffff_ffff_ffff_ffc5, 0], &mut r)
+            .unwrap();
+        assert_eq!(
+            big(&r),
+            BigUint::from(5u8).modpow(
+                &BigUint::from(65537u32),
+                &BigUint::from(0xffff_ffff_ffff_ffc5u64)
+            )
+        );
+        arithmetic.mod_exp(&[7, 0], &[], &[9, 1], &mut r).unwrap();
+        assert_eq!(r, [1, 0]);
+        let mut r = [5];
+        arithmetic.mod_exp(&[0], &[3], &[1], &mut r).unwrap();
+        assert_eq!(r, [0]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut arithmetic = arithmetic();
+        let mut r = [0; 2];
+        assert_eq!(
+            arithmetic.mont_mul(&[1, 0], &[1, 0], &[2, 1], &mut r),
+            Err(Error::EvenModulus)
+        );
+        assert_eq!(
+            arithmetic.mont_mul(&[1, 0], &[1, 2], &[3, 1], &mut r),
+            Err(Error::NotReduced)
+        );
+        assert_eq!(arithmetic.add(&[1, 0], &[1], &mut r), Err(Error::Length));
+        let large = [1u64; 200];
+        let mut r = [0; 200];
+        assert_eq!(
+            arithmetic.mod_exp(&large, &[3], &large, &mut r),
+            Err(Error::TooLarge)
+        );
+    }
+}
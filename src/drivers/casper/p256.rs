@@ -0,0 +1,848 @@
+//! NIST P-256: ECDSA and ECDH, with field multiplications on an arithmetic `Engine`.
+//!
+//! Scalar multiplication uses complete addition formulas (Renes, Costello and
+//! Batina, "Complete addition formulas for prime order elliptic curves") and
+//! processes every bit of the scalar identically, so signing and ECDH run in
+//! constant time.
+//!
+//! Signatures implement the `signature` traits. As the SHA-256 of HASHCRYPT
+//! needs the peripheral, it does not implement `Digest`; hash messages with
+//! `drivers::Sha256` and use `RandomizedPrehashSigner` and `PrehashVerifier`.
+//! Nonces come from the given `CryptoRngCore`, e.g. `Rng<Enabled>`.
+
+use core::{
+    cell::RefCell,
+    convert::{TryFrom, TryInto},
+};
+
+use rand_core::CryptoRngCore;
+use signature::{
+    hazmat::{PrehashVerifier, RandomizedPrehashSigner},
+    DigestVerifier, Keypair, RandomizedDigestSigner, SignatureEncoding,
+};
+
+use super::{CasperEngine, Engine};
+use crate::traits::digest::Digest;
+
+// field elements and scalars, least significant word first
+type Words = [u64; 4];
+
+// layout of engine memory, in words
+const A: usize = 0;
+const B: usize = 4;
+const MODULUS: usize = 8;
+const FACTOR: usize = 12;
+// one word longer than the modulus
+const MULTIPLE: usize = 13;
+// two words longer than the modulus
+const PRODUCT: usize = 18;
+
+const ONE: Words = [1, 0, 0, 0];
+const ZERO: Words = [0; 4];
+
+// curve parameter b, generator coordinates
+const CURVE_B: Words = [
+    0x3bce_3c3e_27d2_604b,
+    0x651d_06b0_cc53_b0f6,
+    0xb3eb_bd55_7698_86bc,
+    0x5ac6_35d8_aa3a_93e7,
+];
+const GENERATOR_X: Words = [
+    0xf4a1_3945_d898_c296,
+    0x7703_7d81_2deb_33a0,
+    0xf8bc_e6e5_63a4_40f2,
+    0x6b17_d1f2_e12c_4247,
+];
+const GENERATOR_Y: Words = [
+    0xcbb6_4068_37bf_51f5,
+    0x2bce_3357_6b31_5ece,
+    0x8ee7_eb4a_7c0f_9e16,
+    0x4fe3_42e2_fe1a_7f9b,
+];
+
+// modulus with its Montgomery constants, R = 2^256
+struct Field {
+    modulus: Words,
+    // -modulus^-1 mod 2^64
+    inverse: u64,
+    // R^2 mod modulus
+    r2: Words,
+}
+
+// coordinates
+const FP: Field = Field {
+    modulus: [
+        0xffff_ffff_ffff_ffff,
+        0x0000_0000_ffff_ffff,
+        0x0000_0000_0000_0000,
+        0xffff_ffff_0000_0001,
+    ],
+    inverse: 1,
+    r2: [
+        0x0000_0000_0000_0003,
+        0xffff_fffb_ffff_ffff,
+        0xffff_ffff_ffff_fffe,
+        0x0000_0004_ffff_fffd,
+    ],
+};
+
+// scalars, modulo the group order
+const FN: Field = Field {
+    modulus: [
+        0xf3b9_cac2_fc63_2551,
+        0xbce6_faad_a717_9e84,
+        0xffff_ffff_ffff_ffff,
+        0xffff_ffff_0000_0000,
+    ],
+    inverse: 0xccd1_c8aa_ee00_bc4f,
+    r2: [
+        0x8324_4c95_be79_eea2,
+        0x4699_799c_49bd_6fa6,
+        0x2845_b239_2b6b_ec59,
+        0x66e1_2d94_f3d9_5620,
+    ],
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Scalar is zero, or not less than the group order
+    InvalidScalar,
+    /// Not the uncompressed SEC1 encoding of a point on the curve
+    InvalidPoint,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Secret scalar, for signing and ECDH; zeroed on drop.
+pub struct SecretKey {
+    scalar: Words,
+}
+
+impl SecretKey {
+    /// Big-endian scalar, in `[1, n - 1]`.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Result<Self> {
+        let scalar = from_bytes(bytes);
+        if scalar == ZERO || !less_than(&scalar, &FN.modulus) {
+            return Err(Error::InvalidScalar);
+        }
+        Ok(Self { scalar })
+    }
+
+    pub fn random(rng: &mut impl CryptoRngCore) -> Self {
+        loop {
+            let mut bytes = [0u8; 32];
+            rng.fill_bytes(&mut bytes);
+            if let Ok(secret) = Self::from_bytes(&bytes) {
+                return secret;
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        to_bytes(&self.scalar)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        for word in self.scalar.iter_mut() {
+            unsafe { core::ptr::write_volatile(word, 0) };
+        }
+    }
+}
+
+/// Point on the curve, other than the point at infinity.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    x: Words,
+    y: Words,
+}
+
+impl PublicKey {
+    /// Uncompressed SEC1 encoding, `04 || x || y`.
+    pub fn to_sec1_bytes(&self) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes[0] = 0x04;
+        bytes[1..33].copy_from_slice(&to_bytes(&self.x));
+        bytes[33..].copy_from_slice(&to_bytes(&self.y));
+        bytes
+    }
+
+    pub fn x(&self) -> [u8; 32] {
+        to_bytes(&self.x)
+    }
+
+    pub fn y(&self) -> [u8; 32] {
+        to_bytes(&self.y)
+    }
+}
+
+/// ECDSA signature `(r, s)`, encoded as `r || s` (big-endian).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Signature {
+    r: Words,
+    s: Words,
+}
+
+impl Signature {
+    /// `r || s`, both in `[1, n - 1]`.
+    pub fn from_bytes(bytes: &[u8; 64]) -> signature::Result<Self> {
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        r.copy_from_slice(&bytes[..32]);
+        s.copy_from_slice(&bytes[32..]);
+        let (r, s) = (from_bytes(&r), from_bytes(&s));
+        for scalar in [r, s].iter() {
+            if *scalar == ZERO || !less_than(scalar, &FN.modulus) {
+                return Err(signature::Error::new());
+            }
+        }
+        Ok(Self { r, s })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&to_bytes(&self.r));
+        bytes[32..].copy_from_slice(&to_bytes(&self.s));
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Signature {
+    type Error = signature::Error;
+
+    fn try_from(bytes: &[u8]) -> signature::Result<Self> {
+        let bytes: &[u8; 64] = bytes.try_into().map_err(|_| signature::Error::new())?;
+        Self::from_bytes(bytes)
+    }
+}
+
+impl From<Signature> for [u8; 64] {
+    fn from(signature: Signature) -> Self {
+        signature.to_bytes()
+    }
+}
+
+impl SignatureEncoding for Signature {
+    type Repr = [u8; 64];
+}
+
+// projective coordinates, in Montgomery form
+#[derive(Copy, Clone)]
+struct ProjectivePoint {
+    x: Words,
+    y: Words,
+    z: Words,
+}
+
+/// The P-256 curve, computing with an arithmetic `Engine`.
+pub struct P256<E: Engine> {
+    engine: RefCell<E>,
+    // in Montgomery form
+    b: Words,
+    generator: ProjectivePoint,
+}
+
+/// P-256 using the CASPER accelerator.
+pub type CasperP256 = P256<CasperEngine>;
+
+impl<E: Engine> P256<E> {
+    pub fn new(engine: E) -> Self {
+        let mut curve = Self {
+            engine: RefCell::new(engine),
+            b: ZERO,
+            generator: ProjectivePoint {
+                x: ZERO,
+                y: ZERO,
+                z: ZERO,
+            },
+        };
+        curve.b = curve.enter_montgomery(&CURVE_B, &FP);
+        curve.generator = curve.projective(&GENERATOR_X, &GENERATOR_Y);
+        curve
+    }
+
+    pub fn release(self) -> E {
+        self.engine.into_inner()
+    }
+
+    pub fn public_key(&self, secret: &SecretKey) -> PublicKey {
+        let point = self.mul_point(&secret.scalar, &self.generator);
+        let (x, y) = self
+            .to_affine(&point)
+            .expect("multiple of the generator by a valid scalar");
+        PublicKey { x, y }
+    }
+
+    /// Decode and validate an uncompressed SEC1 point, `04 || x || y`.
+    pub fn decode_public_key(&self, bytes: &[u8]) -> Result<PublicKey> {
+        if bytes.len() != 65 || bytes[0] != 0x04 {
+            return Err(Error::InvalidPoint);
+        }
+        let mut coordinate = [0u8; 32];
+        coordinate.copy_from_slice(&bytes[1..33]);
+        let x = from_bytes(&coordinate);
+        coordinate.copy_from_slice(&bytes[33..]);
+        let y = from_bytes(&coordinate);
+        if !less_than(&x, &FP.modulus) || !less_than(&y, &FP.modulus) {
+            return Err(Error::InvalidPoint);
+        }
+
+        // y^2 = x^3 - 3 x + b
+        let (xm, ym) = (
+            self.enter_montgomery(&x, &FP),
+            self.enter_montgomery(&y, &FP),
+        );
+        let left = self.mul(&ym, &ym, &FP);
+        let x3 = self.mul(&self.mul(&xm, &xm, &FP), &xm, &FP);
+        let three_x = add(&add(&xm, &xm, &FP), &xm, &FP);
+        let right = add(&sub(&x3, &three_x, &FP), &self.b, &FP);
+        if left != right {
+            return Err(Error::InvalidPoint);
+        }
+        Ok(PublicKey { x, y })
+    }
+
+    /// ECDH: the x-coordinate of `secret * public`.
+    pub fn diffie_hellman(&self, secret: &SecretKey, public: &PublicKey) -> Result<[u8; 32]> {
+        let point = self.projective(&public.x, &public.y);
+        let (x, _) = self
+            .to_affine(&self.mul_point(&secret.scalar, &point))
+            .ok_or(Error::InvalidPoint)?;
+        Ok(to_bytes(&x))
+    }
+
+    pub fn signing_key(&self, secret: SecretKey) -> SigningKey<'_, E> {
+        let public = self.public_key(&secret);
+        SigningKey {
+            curve: self,
+            secret,
+            public,
+        }
+    }
+
+    pub fn verifying_key(&self, public: PublicKey) -> VerifyingKey<'_, E> {
+        VerifyingKey {
+            curve: self,
+            public,
+        }
+    }
+
+    // Montgomery multiplication `a * b / R`, for reduced `a` and `b`
+    fn mul(&self, a: &Words, b: &Words, field: &Field) -> Words {
+        let mut engine = self.engine.borrow_mut();
+        let engine = &mut *engine;
+        for i in 0..4 {
+            engine.write(A + i, a[i]);
+            engine.write(B + i, b[i]);
+            engine.write(MODULUS + i, field.modulus[i]);
+        }
+        for i in 0..9 {
+            engine.write(PRODUCT + i, 0);
+        }
+        for i in 0..4 {
+            engine.mul_add(PRODUCT + i, A, B + i, 4);
+        }
+
+        // add multiples of the modulus to clear the lower half of the product
+        for i in 0..4 {
+            let factor = engine.read(PRODUCT + i).wrapping_mul(field.inverse);
+            engine.write(FACTOR, factor);
+            for j in 0..4 {
+                engine.write(MULTIPLE + j, 0);
+            }
+            engine.mul_add(MULTIPLE, MODULUS, FACTOR, 4);
+            let mut carry = engine.add(PRODUCT + i, MULTIPLE, 5) as u64;
+            for j in PRODUCT + i + 5..PRODUCT + 9 {
+                let (sum, overflow) = engine.read(j).overflowing_add(carry);
+                engine.write(j, sum);
+                carry = overflow as u64;
+            }
+        }
+
+        // the upper half is less than twice the modulus
+        let mut upper = ZERO;
+        for (i, word) in upper.iter_mut().enumerate() {
+            *word = engine.read(PRODUCT + 4 + i);
+        }
+        let top = engine.read(PRODUCT + 8);
+        let (difference, borrow) = sub_words(&upper, &field.modulus);
+        let (_, borrow) = top.overflowing_sub(borrow as u64);
+        select(borrow, &upper, &difference)
+    }
+
+    fn enter_montgomery(&self, a: &Words, field: &Field) -> Words {
+        self.mul(a, &field.r2, field)
+    }
+
+    fn leave_montgomery(&self, a: &Words, field: &Field) -> Words {
+        self.mul(a, &ONE, field)
+    }
+
+    // a^(m - 2) = a^-1, in Montgomery form; the exponent is public
+    fn invert(&self, a: &Words, field: &Field) -> Words {
+        let (exponent, _) = sub_words(&field.modulus, &[2, 0, 0, 0]);
+        let mut result = self.enter_montgomery(&ONE, field);
+        for i in (0..256).rev() {
+            result = self.mul(&result, &result, field);
+            if exponent[i / 64] >> (i % 64) & 1 != 0 {
+                result = self.mul(&result, a, field);
+            }
+        }
+        result
+    }
+
+    fn projective(&self, x: &Words, y: &Words) -> ProjectivePoint {
+        ProjectivePoint {
+            x: self.enter_montgomery(x, &FP),
+            y: self.enter_montgomery(y, &FP),
+            z: self.enter_montgomery(&ONE, &FP),
+        }
+    }
+
+    // affine coordinates, none for the point at infinity
+    fn to_affine(&self, point: &ProjectivePoint) -> Option<(Words, Words)> {
+        if point.z == ZERO {
+            return None;
+        }
+        let z = self.invert(&point.z, &FP);
+        let x = self.mul(&point.x, &z, &FP);
+        let y = self.mul(&point.y, &z, &FP);
+        Some((
+            self.leave_montgomery(&x, &FP),
+            self.leave_montgomery(&y, &FP),
+        ))
+    }
+
+    // algorithm 4 of Renes, Costello and Batina (a = -3), also for p == q
+    fn add_points(&self, p: &ProjectivePoint, q: &ProjectivePoint) -> ProjectivePoint {
+        let mul = |a: &Words, b: &Words| self.mul(a, b, &FP);
+        let add = |a: &Words, b: &Words| add(a, b, &FP);
+        let sub = |a: &Words, b: &Words| sub(a, b, &FP);
+
+        let mut t0 = mul(&p.x, &q.x);
+        let mut t1 = mul(&p.y, &q.y);
+        let mut t2 = mul(&p.z, &q.z);
+        let mut t3 = add(&p.x, &p.y);
+        let mut t4 = add(&q.x, &q.y);
+        t3 = mul(&t3, &t4);
+        t4 = add(&t0, &t1);
+        t3 = sub(&t3, &t4);
+        t4 = add(&p.y, &p.z);
+        let mut x3 = add(&q.y, &q.z);
+        t4 = mul(&t4, &x3);
+        x3 = add(&t1, &t2);
+        t4 = sub(&t4, &x3);
+        x3 = add(&p.x, &p.z);
+        let mut y3 = add(&q.x, &q.z);
+        x3 = mul(&x3, &y3);
+        y3 = add(&t0, &t2);
+        y3 = sub(&x3, &y3);
+        let mut z3 = mul(&self.b, &t2);
+        x3 = sub(&y3, &z3);
+        z3 = add(&x3, &x3);
+        x3 = add(&x3, &z3);
+        z3 = sub(&t1, &x3);
+        x3 = add(&t1, &x3);
+        y3 = mul(&self.b, &y3);
+        t1 = add(&t2, &t2);
+        t2 = add(&t1, &t2);
+        y3 = sub(&y3, &t2);
+        y3 = sub(&y3, &t0);
+        t1 = add(&y3, &y3);
+        y3 = add(&t1, &y3);
+        t1 = add(&t0, &t0);
+        t0 = add(&t1, &t0);
+        t0 = sub(&t0, &t2);
+        t1 = mul(&t4, &y3);
+        t2 = mul(&t0, &y3);
+        y3 = mul(&x3, &z3);
+        y3 = add(&y3, &t2);
+        x3 = mul(&t3, &x3);
+        x3 = sub(&x3, &t1);
+        z3 = mul(&t4, &z3);
+        t1 = mul(&t3, &t0);
+        z3 = add(&z3, &t1);
+
+        ProjectivePoint {
+            x: x3,
+            y: y3,
+            z: z3,
+        }
+    }
+
+    // double and add always, selecting the sum in constant time
+    fn mul_point(&self, scalar: &Words, point: &ProjectivePoint) -> ProjectivePoint {
+        let mut result = ProjectivePoint {
+            x: ZERO,
+            y: self.enter_montgomery(&ONE, &FP),
+            z: ZERO,
+        };
+        for i in (0..256).rev() {
+            result = self.add_points(&result, &result);
+            let sum = self.add_points(&result, point);
+            let skip = scalar[i / 64] >> (i % 64) & 1 == 0;
+            result = ProjectivePoint {
+                x: select(skip, &result.x, &sum.x),
+                y: select(skip, &result.y, &sum.y),
+                z: select(skip, &result.z, &sum.z),
+            };
+        }
+        result
+    }
+
+    // the leftmost 256 bits of the hash, reduced
+    fn hash_to_scalar(prehash: &[u8]) -> signature::Result<Words> {
+        if prehash.len() < 16 {
+            return Err(signature::Error::new());
+        }
+        let mut bytes = [0u8; 32];
+        let len = core::cmp::min(prehash.len(), 32);
+        bytes[32 - len..].copy_from_slice(&prehash[..len]);
+        Ok(reduce(&from_bytes(&bytes), &FN))
+    }
+}
+
+/// ECDSA signing key, see `P256::signing_key`.
+pub struct SigningKey<'a, E: Engine> {
+    curve: &'a P256<E>,
+    secret: SecretKey,
+    public: PublicKey,
+}
+
+impl<E: Engine> SigningKey<'_, E> {
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+}
+
+impl<'a, E: Engine> Keypair for SigningKey<'a, E> {
+    type VerifyingKey = VerifyingKey<'a, E>;
+
+    fn verifying_key(&self) -> VerifyingKey<'a, E> {
+        self.curve.verifying_key(self.public)
+    }
+}
+
+impl<E: Engine> RandomizedPrehashSigner<Signature> for SigningKey<'_, E> {
+    fn sign_prehash_with_rng(
+        &self,
+        rng: &mut impl CryptoRngCore,
+        prehash: &[u8],
+    ) -> signature::Result<Signature> {
+        let curve = self.curve;
+        let z = P256::<E>::hash_to_scalar(prehash)?;
+        let z = curve.enter_montgomery(&z, &FN);
+        let d = curve.enter_montgomery(&self.secret.scalar, &FN);
+        loop {
+            let k = SecretKey::random(rng);
+            let point = curve.mul_point(&k.scalar, &curve.generator);
+            let x = match curve.to_affine(&point) {
+                Some((x, _)) => x,
+                None => continue,
+            };
+            let r = reduce(&x, &FN);
+            if r == ZERO {
+                continue;
+            }
+
+            // s = (z + r d) / k
+            let k = curve.enter_montgomery(&k.scalar, &FN);
+            let rd = curve.mul(&curve.enter_montgomery(&r, &FN), &d, &FN);
+            let s = curve.mul(&add(&z, &rd, &FN), &curve.invert(&k, &FN), &FN);
+            let s = curve.leave_montgomery(&s, &FN);
+            if s == ZERO {
+                continue;
+            }
+            return Ok(Signature { r, s });
+        }
+    }
+}
+
+impl<D: Digest, E: Engine> RandomizedDigestSigner<D, Signature> for SigningKey<'_, E> {
+    fn try_sign_digest_with_rng(
+        &self,
+        rng: &mut impl CryptoRngCore,
+        digest: D,
+    ) -> signature::Result<Signature> {
+        self.sign_prehash_with_rng(rng, &digest.finalize())
+    }
+}
+
+/// ECDSA verifying key, see `P256::verifying_key`.
+pub struct VerifyingKey<'a, E: Engine> {
+    curve: &'a P256<E>,
+    public: PublicKey,
+}
+
+impl<E: Engine> Clone for VerifyingKey<'_, E> {
+    fn clone(&self) -> Self {
+        Self {
+            curve: self.curve,
+            public: self.public,
+        }
+    }
+}
+
+impl<E: Engine> VerifyingKey<'_, E> {
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+}
+
+impl<E: Engine> PrehashVerifier<Signature> for VerifyingKey<'_, E> {
+    fn verify_prehash(&self, prehash: &[u8], signature: &Signature) -> signature::Result<()> {
+        let curve = self.curve;
+        let z = P256::<E>::hash_to_scalar(prehash)?;
+
+        // u1 = z / s, u2 = r / s
+        let w = curve.invert(&curve.enter_montgomery(&signature.s, &FN), &FN);
+        let u1 = curve.mul(&curve.enter_montgomery(&z, &FN), &w, &FN);
+        let u2 = curve.mul(&curve.enter_montgomery(&signature.r, &FN), &w, &FN);
+        let u1 = curve.leave_montgomery(&u1, &FN);
+        let u2 = curve.leave_montgomery(&u2, &FN);
+
+        let q = curve.projective(&self.public.x, &self.public.y);
+        let point = curve.add_points(
+            &curve.mul_point(&u1, &curve.generator),
+            &curve.mul_point(&u2, &q),
+        );
+        match curve.to_affine(&point) {
+            Some((x, _)) if reduce(&x, &FN) == signature.r => Ok(()),
+            _ => Err(signature::Error::new()),
+        }
+    }
+}
+
+impl<D: Digest, E: Engine> DigestVerifier<D, Signature> for VerifyingKey<'_, E> {
+    fn verify_digest(&self, digest: D, signature: &Signature) -> signature::Result<()> {
+        self.verify_prehash(&digest.finalize(), signature)
+    }
+}
+
+// constant time helpers on the CPU
+
+fn from_bytes(bytes: &[u8; 32]) -> Words {
+    let mut words = ZERO;
+    for (i, word) in words.iter_mut().enumerate() {
+        let mut chunk = [0u8; 8];
+        chunk.copy_from_slice(&bytes[32 - 8 * (i + 1)..32 - 8 * i]);
+        *word = u64::from_be_bytes(chunk);
+    }
+    words
+}
+
+fn to_bytes(words: &Words) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, word) in words.iter().enumerate() {
+        bytes[32 - 8 * (i + 1)..32 - 8 * i].copy_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+fn add_words(a: &Words, b: &Words) -> (Words, bool) {
+    let mut sum = ZERO;
+    let mut carry = false;
+    for i in 0..4 {
+        let (word, overflow1) = a[i].overflowing_add(b[i]);
+        let (word, overflow2) = word.overflowing_add(carry as u64);
+        sum[i] = word;
+        carry = overflow1 | overflow2;
+    }
+    (sum, carry)
+}
+
+fn sub_words(a: &Words, b: &Words) -> (Words, bool) {
+    let mut difference = ZERO;
+    let mut borrow = false;
+    for i in 0..4 {
+        let (word, underflow1) = a[i].overflowing_sub(b[i]);
+        let (word, underflow2) = word.overflowing_sub(borrow as u64);
+        difference[i] = word;
+        borrow = underflow1 | underflow2;
+    }
+    (difference, borrow)
+}
+
+// `a` if `choice`, else `b`
+fn select(choice: bool, a: &Words, b: &Words) -> Words {
+    let mask = 0u64.wrapping_sub(choice as u64);
+    let mut result = ZERO;
+    for i in 0..4 {
+        result[i] = (a[i] & mask) | (b[i] & !mask);
+    }
+    result
+}
+
+fn less_than(a: &Words, b: &Words) -> bool {
+    sub_words(a, b).1
+}
+
+// for `a` less than twice the modulus
+fn reduce(a: &Words, field: &Field) -> Words {
+    let (difference, borrow) = sub_words(a, &field.modulus);
+    select(borrow, a, &difference)
+}
+
+fn add(a: &Words, b: &Words, field: &Field) -> Words {
+    let (sum, carry) = add_words(a, b);
+    let (difference, borrow) = sub_words(&sum, &field.modulus);
+    // the sum is less than the modulus if neither carry nor borrow
+    let (_, keep) = (carry as u64).overflowing_sub(borrow as u64);
+    select(keep, &sum, &difference)
+}
+
+fn sub(a: &Words, b: &Words, field: &Field) -> Words {
+    let (difference, borrow) = sub_words(a, b);
+    let correction = select(borrow, &field.modulus, &ZERO);
+    add_words(&difference, &correction).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::casper::SoftEngine;
+    use hex_literal::hex;
+    use p256::ecdsa::signature::{hazmat::PrehashSigner, Verifier};
+    use sha2::Sha256;
+
+    // deterministic, for the tests only
+    struct TestRng(u64);
+
+    impl rand_core::RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl rand_core::CryptoRng for TestRng {}
+
+    // key pair of RFC 6979, appendix A.2.5
+    const SECRET: [u8; 32] =
+        hex!("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721");
+    const PUBLIC_X: [u8; 32] =
+        hex!("60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6");
+    const PUBLIC_Y: [u8; 32] =
+        hex!("7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299");
+
+    fn curve() -> P256<SoftEngine> {
+        P256::new(SoftEngine::new())
+    }
+
+    #[test]
+    fn public_key() {
+        let curve = curve();
+        let public = curve.public_key(&SecretKey::from_bytes(&SECRET).unwrap());
+        assert_eq!(public.x(), PUBLIC_X);
+        assert_eq!(public.y(), PUBLIC_Y);
+        assert_eq!(curve.decode_public_key(&public.to_sec1_bytes()), Ok(public));
+
+        let mut invalid = public.to_sec1_bytes();
+        invalid[64] ^= 1;
+        assert_eq!(curve.decode_public_key(&invalid), Err(Error::InvalidPoint));
+        assert_eq!(
+            SecretKey::from_bytes(&[0; 32]).err(),
+            Some(Error::InvalidScalar)
+        );
+        assert_eq!(
+            SecretKey::from_bytes(&to_bytes(&FN.modulus)).err(),
+            Some(Error::InvalidScalar)
+        );
+    }
+
+    #[test]
+    fn ecdsa_against_p256() {
+        let curve = curve();
+        let mut rng = TestRng(0x853c_49e6_748f_ea9b);
+        let signing_key = curve.signing_key(SecretKey::from_bytes(&SECRET).unwrap());
+        let reference_signing = p256::ecdsa::SigningKey::from_bytes(&SECRET.into()).unwrap();
+        let reference_verifying = reference_signing.verifying_key();
+
+        let message = b"sample";
+        let prehash = Sha256::digest(message);
+
+        // ours, verified by the reference
+        let signature: Signature = signing_key
+            .sign_prehash_with_rng(&mut rng, &prehash)
+            .unwrap();
+        let reference_signature =
+            p256::ecdsa::Signature::from_slice(&signature.to_bytes()).unwrap();
+        assert!(reference_verifying
+            .verify(message, &reference_signature)
+            .is_ok());
+
+        let digest_signature: Signature = signing_key
+            .try_sign_digest_with_rng(&mut rng, Sha256::new_with_prefix(message))
+            .unwrap();
+        assert!(signing_key
+            .verifying_key()
+            .verify_digest(Sha256::new_with_prefix(message), &digest_signature)
+            .is_ok());
+
+        // the reference's, verified by us
+        let reference_signature: p256::ecdsa::Signature =
+            reference_signing.sign_prehash(&prehash).unwrap();
+        let signature = Signature::try_from(reference_signature.to_bytes().as_slice()).unwrap();
+        let verifying_key = signing_key.verifying_key();
+        assert!(verifying_key.verify_prehash(&prehash, &signature).is_ok());
+        assert!(verifying_key
+            .verify_prehash(&Sha256::digest(b"other"), &signature)
+            .is_err());
+
+        let mut forged = signature.to_bytes();
+        forged[63] ^= 1;
+        let forged = Signature::from_bytes(&forged).unwrap();
+        assert!(verifying_key.verify_prehash(&prehash, &forged).is_err());
+    }
+
+    #[test]
+    fn ecdh_against_p256() {
+        let curve = curve();
+        let mut rng = TestRng(0xda3e_39cb_94b9_5bdb);
+        let ours = SecretKey::random(&mut rng);
+        let theirs = SecretKey::random(&mut rng);
+
+        let reference_ours = p256::SecretKey::from_bytes(&ours.to_bytes().into()).unwrap();
+        let reference_theirs = p256::SecretKey::from_bytes(&theirs.to_bytes().into()).unwrap();
+        let reference_shared = p256::ecdh::diffie_hellman(
+            reference_ours.to_nonzero_scalar(),
+            reference_theirs.public_key().as_affine(),
+        );
+
+        let their_public = curve
+            .decode_public_key(
+                p256::elliptic_curve::sec1::ToEncodedPoint::to_encoded_point(
+                    &reference_theirs.public_key(),
+                    false,
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(their_public, curve.public_key(&theirs));
+        let shared = curve.diffie_hellman(&ours, &their_public).unwrap();
+        assert_eq!(&shared[..], reference_shared.raw_secret_bytes().as_slice());
+        assert_eq!(
+            curve.diffie_hellman(&theirs, &curve.public_key(&ours)),
+            Ok(shared)
+        );
+    }
+}
@@ -0,0 +1,445 @@
+//! RSA signature verification (RFC 8017) with SHA-256: PKCS#1 v1.5 and PSS.
+//!
+//! The modular exponentiation runs on an arithmetic `Engine`, hashing (of
+//! the message, and for the mask generation of PSS) on a SHA-256
+//! implementation, typically `drivers::Sha256`.
+//!
+//! Public keys are parsed in place from DER, e.g. in flash, either as PKCS#1
+//! `RSAPublicKey` or wrapped in a `SubjectPublicKeyInfo`.
+
+use digest::{FixedOutputReset, Output, OutputSizeUser, Reset, Update};
+
+use super::{Arithmetic, CasperEngine, Engine};
+use crate::{drivers::sha::Sha256, traits::digest::generic_array::typenum::U32};
+
+// limits of the modulus
+const MIN_BYTES: usize = 256;
+const MAX_BYTES: usize = 512;
+const MAX_WORDS: usize = MAX_BYTES / 8;
+
+const HASH_LEN: usize = 32;
+
+// DER of `DigestInfo` for SHA-256, up to the hash
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+
+// DER of `AlgorithmIdentifier` contents for rsaEncryption, with NULL parameters
+const RSA_ENCRYPTION: [u8; 13] = [
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Malformed DER, or not an RSA public key
+    InvalidKey,
+    /// Modulus of other than 2048 to 4096 bits, or public exponent of more than 64 bits
+    UnsupportedKey,
+    /// The signature is not valid for the message and key
+    Verification,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// RSA public key, borrowing its modulus from the DER encoding.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RsaPublicKey<'a> {
+    // big-endian, without leading zeros
+    modulus: &'a [u8],
+    exponent: u64,
+}
+
+impl<'a> RsaPublicKey<'a> {
+    /// Parse a PKCS#1 `RSAPublicKey`.
+    pub fn from_pkcs1_der(der: &'a [u8]) -> Result<Self> {
+        let (key, rest) = parse(der, SEQUENCE)?;
+        if !rest.is_empty() {
+            return Err(Error::InvalidKey);
+        }
+        let (modulus, key) = parse_unsigned(key)?;
+        let (exponent, key) = parse_unsigned(key)?;
+        if !key.is_empty() {
+            return Err(Error::InvalidKey);
+        }
+
+        if modulus.len() < MIN_BYTES || modulus.len() > MAX_BYTES || exponent.len() > 8 {
+            return Err(Error::UnsupportedKey);
+        }
+        let mut bytes = [0u8; 8];
+        bytes[8 - exponent.len()..].copy_from_slice(exponent);
+        let exponent = u64::from_be_bytes(bytes);
+        if modulus[modulus.len() - 1] & 1 == 0 || exponent < 3 {
+            return Err(Error::InvalidKey);
+        }
+        Ok(Self { modulus, exponent })
+    }
+
+    /// Parse an RSA `SubjectPublicKeyInfo`, as in X.509 certificates.
+    pub fn from_public_key_der(der: &'a [u8]) -> Result<Self> {
+        let (info, rest) = parse(der, SEQUENCE)?;
+        if !rest.is_empty() {
+            return Err(Error::InvalidKey);
+        }
+        let (algorithm, info) = parse(info, SEQUENCE)?;
+        if algorithm != RSA_ENCRYPTION {
+            return Err(Error::InvalidKey);
+        }
+        let (key, info) = parse(info, BIT_STRING)?;
+        // no unused bits
+        match key.split_first() {
+            Some((0, key)) if info.is_empty() => Self::from_pkcs1_der(key),
+            _ => Err(Error::InvalidKey),
+        }
+    }
+
+    /// Size of the modulus (and signatures) in bytes.
+    pub fn size(&self) -> usize {
+        self.modulus.len()
+    }
+
+    pub fn exponent(&self) -> u64 {
+        self.exponent
+    }
+
+    fn bits(&self) -> usize {
+        8 * self.modulus.len() - self.modulus[0].leading_zeros() as usize
+    }
+}
+
+/// RSA signature verification, see module documentation.
+pub struct Rsa<E: Engine, D> {
+    arithmetic: Arithmetic<E>,
+    sha256: D,
+}
+
+/// RSA signature verification using the CASPER accelerator and HASHCRYPT.
+pub type CasperRsa<'a> = Rsa<CasperEngine, Sha256<'a>>;
+
+impl<E, D> Rsa<E, D>
+where
+    E: Engine,
+    D: Update + FixedOutputReset + OutputSizeUser<OutputSize = U32>,
+{
+    pub fn new(arithmetic: Arithmetic<E>, sha256: D) -> Self {
+        Self { arithmetic, sha256 }
+    }
+
+    pub fn release(self) -> (Arithmetic<E>, D) {
+        (self.arithmetic, self.sha256)
+    }
+
+    /// Verify an RSASSA-PKCS1-v1_5 signature of `message`.
+    pub fn verify_pkcs1v15(
+        &mut self,
+        key: &RsaPublicKey<'_>,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let hash = self.hash(&[message]);
+        self.verify_pkcs1v15_prehash(key, &hash, signature)
+    }
+
+    /// Verify an RSASSA-PKCS1-v1_5 signature of a SHA-256 `hash`.
+    pub fn verify_pkcs1v15_prehash(
+        &mut self,
+        key: &RsaPublicKey<'_>,
+        hash: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        if hash.len() != HASH_LEN {
+            return Err(Error::Verification);
+        }
+        let mut buffer = [0u8; MAX_BYTES];
+        let em = self.encoded_message(key, signature, &mut buffer)?;
+
+        // 00 01 ff .. ff 00 || DigestInfo || hash
+        let mut expected = [0xffu8; MAX_BYTES];
+        let expected = &mut expected[..em.len()];
+        let t = em.len() - HASH_LEN - SHA256_DIGEST_INFO.len();
+        expected[0] = 0x00;
+        expected[1] = 0x01;
+        expected[t - 1] = 0x00;
+        expected[t..em.len() - HASH_LEN].copy_from_slice(&SHA256_DIGEST_INFO);
+        expected[em.len() - HASH_LEN..].copy_from_slice(hash);
+        if em != expected {
+            return Err(Error::Verification);
+        }
+        Ok(())
+    }
+
+    /// Verify an RSASSA-PSS signature of `message`, with MGF1 and any salt length.
+    pub fn verify_pss(
+        &mut self,
+        key: &RsaPublicKey<'_>,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let hash = self.hash(&[message]);
+        self.verify_pss_prehash(key, &hash, signature)
+    }
+
+    /// Verify an RSASSA-PSS signature of a SHA-256 `hash`, see `verify_pss`.
+    pub fn verify_pss_prehash(
+        &mut self,
+        key: &RsaPublicKey<'_>,
+        hash: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        if hash.len() != HASH_LEN {
+            return Err(Error::Verification);
+        }
+        let mut buffer = [0u8; MAX_BYTES];
+        let em = self.encoded_message(key, signature, &mut buffer)?;
+
+        // the encoded message has one bit less than the modulus
+        let em_bits = key.bits() - 1;
+        let em_len = em_bits.div_ceil(8);
+        let (leading, em) = em.split_at_mut(em.len() - em_len);
+        if leading.iter().any(|byte| *byte != 0) || em_len < HASH_LEN + 2 || em[em_len - 1] != 0xbc
+        {
+            return Err(Error::Verification);
+        }
+        let (db, h) = em[..em_len - 1].split_at_mut(em_len - HASH_LEN - 1);
+        let top_mask = 0xffu8 >> (8 * em_len - em_bits);
+        if db[0] & !top_mask != 0 {
+            return Err(Error::Verification);
+        }
+
+        // MGF1
+        for (counter, chunk) in db.chunks_mut(HASH_LEN).enumerate() {
+            let mask = self.hash(&[h, &(counter as u32).to_be_bytes()]);
+            for (byte, mask) in chunk.iter_mut().zip(mask.iter()) {
+                *byte ^= mask;
+            }
+        }
+        db[0] &= top_mask;
+
+        // zeros || 01 || salt
+        let separator = db
+            .iter()
+            .position(|byte| *byte != 0)
+            .ok_or(Error::Verification)?;
+        if db[separator] != 0x01 {
+            return Err(Error::Verification);
+        }
+        let salt = &db[separator + 1..];
+
+        if self.hash(&[&[0; 8], hash, salt]).as_slice() != &h[..] {
+            return Err(Error::Verification);
+        }
+        Ok(())
+    }
+
+    fn hash(&mut self, parts: &[&[u8]]) -> Output<D> {
+        Reset::reset(&mut self.sha256);
+        for part in parts {
+            self.sha256.update(part);
+        }
+        self.sha256.finalize_fixed_reset()
+    }
+
+    // RSAVP1: signature^e mod n, as many bytes as the modulus
+    fn encoded_message<'b>(
+        &mut self,
+        key: &RsaPublicKey<'_>,
+        signature: &[u8],
+        buffer: &'b mut [u8; MAX_BYTES],
+    ) -> Result<&'b mut [u8]> {
+        let len = key.size();
+        if signature.len() != len {
+            return Err(Error::Verification);
+        }
+        let n = len.div_ceil(8);
+        let mut modulus = [0u64; MAX_WORDS];
+        let mut base = [0u64; MAX_WORDS];
+        let mut result = [0u64; MAX_WORDS];
+        from_bytes(key.modulus, &mut modulus[..n]);
+        from_bytes(signature, &mut base[..n]);
+
+        self.arithmetic
+            .mod_exp(&base[..n], &[key.exponent], &modulus[..n], &mut result[..n])
+            .map_err(|error| match error {
+                // signature not less than the modulus
+                super::Error::NotReduced => Error::Verification,
+                _ => Error::UnsupportedKey,
+            })?;
+
+        let em = &mut buffer[..len];
+        to_bytes(&result[..n], em);
+        Ok(em)
+    }
+}
+
+// big-endian bytes to little-endian words, that may be longer
+fn from_bytes(bytes: &[u8], words: &mut [u64]) {
+    for word in words.iter_mut() {
+        *word = 0;
+    }
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        words[i / 8] |= (*byte as u64) << (8 * (i % 8));
+    }
+}
+
+fn to_bytes(words: &[u64], bytes: &mut [u8]) {
+    for (i, byte) in bytes.iter_mut().rev().enumerate() {
+        *byte = (words[i / 8] >> (8 * (i % 8))) as u8;
+    }
+}
+
+// DER element with the given tag: its contents, and what follows
+fn parse(der: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    let (&actual, der) = der.split_first().ok_or(Error::InvalidKey)?;
+    let (&first, der) = der.split_first().ok_or(Error::InvalidKey)?;
+    if actual != tag {
+        return Err(Error::InvalidKey);
+    }
+    // minimal encodings of lengths up to 0xffff
+    let (len, der) = match first {
+        0..=0x7f => (first as usize, der),
+        0x81 => match der.split_first() {
+            Some((&len, der)) if len >= 0x80 => (len as usize, der),
+            _ => return Err(Error::InvalidKey),
+        },
+        0x82 if der.len() >= 2 => {
+            let len = u16::from_be_bytes([der[0], der[1]]) as usize;
+            if len < 0x100 {
+                return Err(Error::InvalidKey);
+            }
+            (len, &der[2..])
+        }
+        _ => return Err(Error::InvalidKey),
+    };
+    if der.len() < len {
+        return Err(Error::InvalidKey);
+    }
+    Ok(der.split_at(len))
+}
+
+// DER INTEGER that is positive, without the leading zero byte
+fn parse_unsigned(der: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (integer, rest) = parse(der, INTEGER)?;
+    match integer {
+        [] => Err(Error::InvalidKey),
+        [first, ..] if first & 0x80 != 0 => Err(Error::InvalidKey),
+        [0, second, ..] if second & 0x80 == 0 => Err(Error::InvalidKey),
+        [0] => Ok((integer, rest)),
+        [0, integer @ ..] => Ok((integer, rest)),
+        _ => Ok((integer, rest)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::casper::SoftEngine;
+
+    const MESSAGE: &[u8] = b"vendor-signed payload";
+
+    // key, and signatures of `MESSAGE` by OpenSSL; PSS with 32 bytes of salt
+    struct Vectors {
+        pkcs1: &'static [u8],
+        spki: &'static [u8],
+        pkcs1v15: &'static [u8],
+        pss: &'static [u8],
+    }
+
+    const VECTORS: [Vectors; 2] = [
+        Vectors {
+            pkcs1: include_bytes!("testdata/rsa2048-pkcs1.der"),
+            spki: include_bytes!("testdata/rsa2048-spki.der"),
+            pkcs1v15: include_bytes!("testdata/rsa2048-pkcs1v15.sig"),
+            pss: include_bytes!("testdata/rsa2048-pss.sig"),
+        },
+        Vectors {
+            pkcs1: include_bytes!("testdata/rsa4096-pkcs1.der"),
+            spki: include_bytes!("testdata/rsa4096-spki.der"),
+            pkcs1v15: include_bytes!("testdata/rsa4096-pkcs1v15.sig"),
+            pss: include_bytes!("testdata/rsa4096-pss.sig"),
+        },
+    ];
+
+    fn rsa() -> Rsa<SoftEngine, sha2::Sha256> {
+        Rsa::new(Arithmetic::new(SoftEngine::new()), Default::default())
+    }
+
+    #[test]
+    fn parse_keys() {
+        for (i, Vectors { pkcs1, spki, .. }) in VECTORS.iter().enumerate() {
+            let key = RsaPublicKey::from_pkcs1_der(pkcs1).unwrap();
+            assert_eq!(key.size(), 256 << i);
+            assert_eq!(key.exponent(), 65537);
+            assert_eq!(RsaPublicKey::from_public_key_der(spki), Ok(key));
+
+            assert_eq!(RsaPublicKey::from_pkcs1_der(spki), Err(Error::InvalidKey));
+            assert_eq!(
+                RsaPublicKey::from_public_key_der(pkcs1),
+                Err(Error::InvalidKey)
+            );
+            assert_eq!(
+                RsaPublicKey::from_pkcs1_der(&pkcs1[..pkcs1.len() - 1]),
+                Err(Error::InvalidKey)
+            );
+        }
+        // 8 bit modulus
+        assert_eq!(
+            RsaPublicKey::from_pkcs1_der(&[0x30, 0x07, 0x02, 0x02, 0x00, 0xcb, 0x02, 0x01, 0x03]),
+            Err(Error::UnsupportedKey)
+        );
+    }
+
+    #[test]
+    fn verify() {
+        let mut rsa = rsa();
+        for Vectors {
+            pkcs1,
+            pkcs1v15,
+            pss,
+            ..
+        } in VECTORS.iter()
+        {
+            let key = RsaPublicKey::from_pkcs1_der(pkcs1).unwrap();
+            assert_eq!(rsa.verify_pkcs1v15(&key, MESSAGE, pkcs1v15), Ok(()));
+            assert_eq!(rsa.verify_pss(&key, MESSAGE, pss), Ok(()));
+
+            // wrong message, scheme, or signature
+            assert_eq!(
+                rsa.verify_pkcs1v15(&key, b"other payload", pkcs1v15),
+                Err(Error::Verification)
+            );
+            assert_eq!(
+                rsa.verify_pss(&key, b"other payload", pss),
+                Err(Error::Verification)
+            );
+            assert_eq!(
+                rsa.verify_pss(&key, MESSAGE, pkcs1v15),
+                Err(Error::Verification)
+            );
+            assert_eq!(
+                rsa.verify_pkcs1v15(&key, MESSAGE, pss),
+                Err(Error::Verification)
+            );
+            let mut forged = [0u8; MAX_BYTES];
+            let forged = &mut forged[..pss.len()];
+            forged.copy_from_slice(pss);
+            forged[pss.len() / 2] ^= 1;
+            assert_eq!(
+                rsa.verify_pss(&key, MESSAGE, forged),
+                Err(Error::Verification)
+            );
+            assert_eq!(
+                rsa.verify_pss(&key, MESSAGE, &pss[1..]),
+                Err(Error::Verification)
+            );
+            // not less than the modulus
+            assert_eq!(
+                rsa.verify_pss(&key, MESSAGE, &[0xff; MAX_BYTES][..pss.len()]),
+                Err(Error::Verification)
+            );
+        }
+    }
+}
@@ -1,12 +1,27 @@
-use core::convert::TryInto;
+use core::{cell::Cell, cmp::min, convert::TryInto, ops::Range};
 // use cortex_m_semihosting::hprintln;
 
 use crate::{
     peripherals::flash::Flash,
-    traits::flash::{Error, Read, Result, WriteErase},
-    typestates::init_state::Enabled,
+    traits::flash::{EccStatus, Error, Read, Result, WriteErase},
+    typestates::{
+        flash_state::{LockState, Locked, Unlocked},
+        init_state::Enabled,
+    },
 };
 
+pub mod kv;
+pub use kv::KvStore;
+
+pub mod rom;
+pub use rom::RomFlash;
+
+pub mod sim;
+pub use sim::SimFlash;
+
+pub mod update;
+pub use update::{UpdateBuilder, Updater};
+
 pub use generic_array::{
     typenum::{U16, U512, U8},
     GenericArray,
@@ -21,9 +36,37 @@ pub use generic_array::{
 pub const READ_SIZE: usize = 16;
 pub const WRITE_SIZE: usize = 512;
 pub const PAGE_SIZE: usize = 512;
+// 640KB of flash
+const PAGES: usize = 640 * 1024 / PAGE_SIZE;
+// physical words per page
+const PAGE_WORDS: usize = PAGE_SIZE / READ_SIZE;
+
+/// Read margin for `FlashGordon::margin_check`, passed to the controller in DATAW0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Margin {
+    Normal = 0,
+    User = 1,
+    Factory = 2,
+}
 
-pub struct FlashGordon {
+/// ECC statistics of the flash controller, see `FlashGordon::ecc_report`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EccReport {
+    /// Number of corrected reads since reset
+    pub corrections: u32,
+}
+
+/// Driver for the internal flash.
+///
+/// Pages can be write-protected with `lock`, see there.
+pub struct FlashGordon<State: LockState = Unlocked> {
     flash: Flash<Enabled>,
+    listening: bool,
+    // last correction count seen, to classify ECC events
+    ecc_corrections: Cell<u32>,
+    // one bit per write-protected page
+    locked: [u32; PAGES / 32],
+    _state: State,
 }
 
 impl FlashGordon {
@@ -37,7 +80,61 @@ impl FlashGordon {
         // first thing to check! legal command failed
         debug_assert!(flash.raw.int_status.read().fail().bit_is_clear());
 
-        FlashGordon { flash }
+        let flash_gordon = FlashGordon {
+            flash,
+            listening: false,
+            ecc_corrections: Cell::new(0),
+            locked: [0; PAGES / 32],
+            _state: Unlocked,
+        };
+        if let Ok(report) = flash_gordon.ecc_report() {
+            flash_gordon.ecc_corrections.set(report.corrections);
+        }
+        flash_gordon
+    }
+}
+
+// Best-effort check whether the caller executes from the pages `pages.start..pages.end`,
+// in the non-secure or secure alias of flash.  Only the caller's PC is sampled,
+// it says nothing about its callees or interrupt handlers.
+#[inline(always)]
+fn executing_from(pages: Range<usize>) -> bool {
+    // secure alias of flash starts at 0x1000_0000
+    let pc = (cortex_m::register::pc::read() & !0x1000_0000) as usize;
+    (pages.start * PAGE_SIZE..pages.end * PAGE_SIZE).contains(&pc)
+}
+
+impl<State: LockState> FlashGordon<State> {
+    /// Write-protect the pages `pages.start..pages.end` until reset.
+    ///
+    /// A locked `FlashGordon` can only read, erasing and programming goes through
+    /// a `FlashRegion` of unlocked pages, see `FlashGordon::region`. There is no
+    /// way to unlock pages again, short of a reset. The flash controller itself
+    /// offers no such protection, so this is enforced by the driver only; code
+    /// bypassing it is not stopped.
+    pub fn lock(self, pages: Range<usize>) -> FlashGordon<Locked> {
+        let mut locked = self.locked;
+        for page in pages.start..min(pages.end, PAGES) {
+            locked[page / 32] |= 1 << (page % 32);
+        }
+        FlashGordon {
+            flash: self.flash,
+            listening: self.listening,
+            ecc_corrections: self.ecc_corrections,
+            locked,
+            _state: Locked,
+        }
+    }
+
+    pub fn is_locked(&self, page: usize) -> bool {
+        page < PAGES && self.locked[page / 32] & (1 << (page % 32)) != 0
+    }
+
+    fn check_unlocked(&self, pages: Range<usize>) -> Result {
+        match pages.into_iter().any(|page| self.is_locked(page)) {
+            true => Err(Error::WriteProtected),
+            false => Ok(()),
+        }
     }
 
     fn clear_status(&self) {
@@ -71,9 +168,356 @@ impl FlashGordon {
         Ok(())
     }
 
+    pub fn read_u128(&mut self, address: usize) -> Result<u128> {
+        let mut buf = [0u8; 16];
+        self.read(address, &mut buf)?;
+        Ok(u128::from_ne_bytes(buf))
+    }
+
+    /// Read `buf.len()` bytes starting at `address`, which need not be aligned.
+    ///
+    /// Each physical word (16 bytes) touched is read in full, and the
+    /// requested bytes copied out.
+    pub fn read_unaligned(&self, address: usize, buf: &mut [u8]) -> Result<EccStatus> {
+        let mut word = GenericArray::<u8, U16>::default();
+        let mut status = EccStatus::Clean;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let current = address + offset;
+            let word_address = current & !(READ_SIZE - 1);
+            let start = current - word_address;
+            let len = min(READ_SIZE - start, buf.len() - offset);
+
+            if self.read_word(word_address, &mut word)? == EccStatus::Corrected {
+                status = EccStatus::Corrected;
+            }
+            buf[offset..][..len].copy_from_slice(&word[start..][..len]);
+            offset += len;
+        }
+        Ok(status)
+    }
+
+    /// Check whether the pages `pages.start..pages.end` are erased.
+    ///
+    /// As for `margin_check` and `checksum`, an empty range is `Error::Illegal`.
+    pub fn is_blank(&self, pages: Range<usize>) -> Result<bool> {
+        if pages.is_empty() {
+            return Err(Error::Illegal);
+        }
+        let (starta, stopa) = Self::word_range(pages);
+        self.start_command(FlashCommands::BlankCheck, starta, stopa)?;
+        Self::passed(self.finish_command())
+    }
+
+    /// Check whether the pages `pages.start..pages.end` read back correctly
+    /// with the given read margin.
+    ///
+    /// An empty range is `Error::Illegal`.
+    pub fn margin_check(&self, pages: Range<usize>, margin: Margin) -> Result<bool> {
+        if pages.is_empty() {
+            return Err(Error::Illegal);
+        }
+        let (starta, stopa) = Self::word_range(pages);
+        self.flash.raw.dataw[0].write(|w| unsafe { w.bits(margin as u32) });
+        self.start_command(FlashCommands::MarginCheck, starta, stopa)?;
+        Self::passed(self.finish_command())
+    }
+
+    /// Hardware checksum over the pages `pages.start..pages.end`.
+    ///
+    /// An empty range is `Error::Illegal`.
+    pub fn checksum(&self, pages: Range<usize>) -> Result<[u32; 4]> {
+        if pages.is_empty() {
+            return Err(Error::Illegal);
+        }
+        let (starta, stopa) = Self::word_range(pages);
+        self.start_command(FlashCommands::Checksum, starta, stopa)?;
+        self.finish_command()?;
+
+        let mut checksum = [0u32; 4];
+        for (i, word) in checksum.iter_mut().enumerate() {
+            *word = self.flash.raw.dataw[i].read().bits();
+        }
+        Ok(checksum)
+    }
+
+    /// ECC statistics, as reported by the flash controller.
+    ///
+    /// A rising correction count indicates degrading flash.
+    pub fn ecc_report(&self) -> Result<EccReport> {
+        self.start_command(FlashCommands::ReportEcc, 0, 0)?;
+        self.finish_command()?;
+        Ok(EccReport {
+            corrections: self.flash.raw.dataw[0].read().bits(),
+        })
+    }
+
+    /// Check for completion of a command started by `start_erase_range`
+    /// or `start_write_page`.
+    ///
+    /// Once done, this masks the flash interrupt again (see `listen`).
+    pub fn wait(&mut self) -> nb::Result<(), Error> {
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.flash.raw.int_clr_enable.write(|w| w.done().set_bit());
+        self.status().map_err(nb::Error::Other)
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.flash.raw.int_status.read().done().bit_is_clear()
+    }
+
+    /// Raise an interrupt when commands started by `start_erase_range`
+    /// or `start_write_page` complete.
+    ///
+    /// The flash controller shares IRQ 0 (`WDT_BOD`) with the watchdog and
+    /// brown-out detector. As DONE stays set while the controller is idle,
+    /// the interrupt is only unmasked while a command is running: the
+    /// handler must call `wait`, which masks it again.
+    pub fn listen(&mut self) {
+        self.listening = true;
+    }
+
+    pub fn unlisten(&mut self) {
+        self.listening = false;
+        self.flash.raw.int_clr_enable.write(|w| w.done().set_bit());
+    }
+
+    fn enable_done_interrupt(&self) {
+        if self.listening {
+            self.flash.raw.int_set_enable.write(|w| w.done().set_bit());
+        }
+    }
+
+    // first and last physical word of a (non-empty) range of pages
+    fn word_range(pages: Range<usize>) -> (usize, usize) {
+        (pages.start * PAGE_WORDS, pages.end * PAGE_WORDS - 1)
+    }
+
+    // checking commands signal a negative outcome via the `fail` flag
+    fn passed(result: Result) -> Result<bool> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(Error::Failure) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Command parameters in `dataw` must be set before calling this.
+    fn start_command(&self, command: FlashCommands, starta: usize, stopa: usize) -> Result {
+        let flash = &self.flash.raw;
+        if flash.int_status.read().done().bit_is_clear() {
+            return Err(Error::Busy);
+        }
+        self.clear_status();
+
+        flash
+            .starta
+            .write(|w| unsafe { w.starta().bits(starta as u32) });
+        flash
+            .stopa
+            .write(|w| unsafe { w.stopa().bits(stopa as u32) });
+        flash.cmd.write(|w| unsafe { w.bits(command as u32) });
+        Ok(())
+    }
+
+    fn finish_command(&self) -> Result {
+        while self.flash.raw.int_status.read().done().bit_is_clear() {}
+        self.status()
+    }
+
+    // this reads 16B or one flash word
+    // address is in bytes, whereas starta expects address in flash words
+    // so starta = address / 16 = address >> 4
+    fn read_word(&self, address: usize, array: &mut GenericArray<u8, U16>) -> Result<EccStatus> {
+        let flash = &self.flash.raw;
+
+        if flash.int_status.read().done().bit_is_clear() {
+            return Err(Error::Busy);
+        }
+        self.clear_status();
+
+        let addr = address as u32;
+        debug_assert!(addr & (READ_SIZE as u32 - 1) == 0);
+
+        flash
+            .starta
+            .write(|w| unsafe { w.starta().bits(addr >> 4) });
+        // want to have normal reads
+        flash.dataw[0].write(|w| unsafe { w.bits(0) });
+        flash
+            .cmd
+            .write(|w| unsafe { w.bits(FlashCommands::ReadSingleWord as u32) });
+        while flash.int_status.read().done().bit_is_clear() {
+            continue;
+        }
+
+        let status = flash.int_status.read();
+        if status.err().bit_is_set() {
+            return Err(Error::Illegal);
+        }
+        if status.fail().bit_is_set() {
+            return Err(Error::Failure);
+        }
+
+        // each dataw[i] now contains 4 bytes
+        for (i, chunk) in array.chunks_mut(4).enumerate() {
+            chunk.copy_from_slice(&flash.dataw[i].read().bits().to_ne_bytes());
+        }
+
+        if status.ecc_err().bit_is_clear() {
+            return Ok(EccStatus::Clean);
+        }
+        // the ECC flag does not say whether the error was corrected,
+        // but only corrections are counted
+        let corrections = self.ecc_report()?.corrections;
+        if corrections != self.ecc_corrections.replace(corrections) {
+            Ok(EccStatus::Corrected)
+        } else {
+            Err(Error::EccError)
+        }
+    }
+
+    fn patch(&mut self, address: usize, data: &[u8]) -> Result {
+        let mut page = GenericArray::<u8, U512>::default();
+        let mut offset = 0;
+        while offset < data.len() {
+            let current = address + offset;
+            let page_address = current & !(PAGE_SIZE - 1);
+            let start = current - page_address;
+            let len = min(PAGE_SIZE - start, data.len() - offset);
+            let chunk = &data[offset..][..len];
+            offset += len;
+
+            self.read_unaligned(page_address, &mut page)?;
+            if page[start..][..len] == *chunk {
+                continue;
+            }
+            page[start..][..len].copy_from_slice(chunk);
+
+            let index = page_address / PAGE_SIZE;
+            self.erase_pages(index..index + 1)?;
+            self.program_page(page_address, &page)?;
+        }
+        Ok(())
+    }
+
+    fn erase_pages(&mut self, pages: Range<usize>) -> Result {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        // for erasure, stopa is the first physical word of the last page
+        self.start_command(
+            FlashCommands::EraseRange,
+            pages.start * PAGE_WORDS,
+            (pages.end - 1) * PAGE_WORDS,
+        )?;
+        self.finish_command()
+    }
+
+    fn start_erase_pages(&mut self, pages: Range<usize>) -> Result {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        if executing_from(pages.clone()) {
+            return Err(Error::Illegal);
+        }
+        self.start_command(
+            FlashCommands::EraseRange,
+            pages.start * PAGE_WORDS,
+            (pages.end - 1) * PAGE_WORDS,
+        )?;
+        self.enable_done_interrupt();
+        Ok(())
+    }
+
+    fn start_program_page(&mut self, address: usize, page: &GenericArray<u8, U512>) -> Result {
+        if address % PAGE_SIZE != 0 {
+            return Err(Error::Illegal);
+        }
+        let first_page = address / PAGE_SIZE;
+        if executing_from(first_page..first_page + 1) {
+            return Err(Error::Illegal);
+        }
+
+        let starta = address / READ_SIZE;
+        for (i, chunk) in page.chunks(READ_SIZE).enumerate() {
+            for (j, word) in chunk.chunks(4).enumerate() {
+                self.flash.raw.dataw[j]
+                    .write(|w| unsafe { w.bits(u32::from_ne_bytes(word.try_into().unwrap())) });
+            }
+            self.start_command(FlashCommands::Write, starta + i, starta + i)?;
+            self.finish_command()?;
+        }
+
+        self.start_command(FlashCommands::Program, starta, starta)?;
+        self.enable_done_interrupt();
+        Ok(())
+    }
+
+    fn program_page(&mut self, address: usize, array: &GenericArray<u8, U512>) -> Result {
+        // hprintln!("native write to {} of {:?} (first 16)", address, &array[..16]);
+        let flash = &self.flash.raw;
+        if flash.int_status.read().done().bit_is_clear() {
+            return Err(Error::Busy);
+        }
+        self.clear_status();
+
+        // maybe check the page is erased?
+
+        // write one physical word (16 bytes) at a time
+        for (i, chunk) in array.chunks(16).enumerate() {
+            let starta = (address >> 4) + i;
+            flash
+                .starta
+                .write(|w| unsafe { w.starta().bits(starta as u32) });
+
+            for (j, word) in chunk.chunks(4).enumerate() {
+                flash.dataw[j]
+                    .write(|w| unsafe { w.bits(u32::from_ne_bytes(word.try_into().unwrap())) });
+            }
+
+            flash
+                .cmd
+                .write(|w| unsafe { w.bits(FlashCommands::Write as u32) });
+            // flash.cmd.write(|w| unsafe { w.bits(FlashCommands::WriteProgram as u32) });
+            while flash.int_status.read().done().bit_is_clear() {}
+            debug_assert!(flash.int_status.read().err().bit_is_clear());
+            debug_assert!(flash.int_status.read().fail().bit_is_clear());
+            self.status()?;
+        }
+        self.clear_status();
+
+        let starta = address >> 4;
+        flash
+            .starta
+            .write(|w| unsafe { w.starta().bits(starta as u32) });
+        flash
+            .cmd
+            .write(|w| unsafe { w.bits(FlashCommands::Program as u32) });
+        while flash.int_status.read().done().bit_is_clear() {}
+        debug_assert!(flash.int_status.read().err().bit_is_clear());
+        debug_assert!(flash.int_status.read().fail().bit_is_clear());
+        self.status()?;
+
+        Ok(())
+    }
+}
+
+impl<State: LockState> Read<U16> for FlashGordon<State> {
+    fn read_native(&self, address: usize, array: &mut GenericArray<u8, U16>) -> Result<EccStatus> {
+        // hprintln!("native read from {} of {:?} (first 16)", address, &array[..16]);
+        self.read_word(address, array)
+    }
+}
+
+impl FlashGordon<Unlocked> {
     pub fn just_program_at(&mut self, address: usize) -> Result {
         let flash = &self.flash.raw;
-        assert!(flash.int_status.read().done().bit_is_set());
+        if flash.int_status.read().done().bit_is_clear() {
+            return Err(Error::Busy);
+        }
         self.clear_status();
 
         flash.event.write(|w| w.rst().set_bit());
@@ -96,9 +540,11 @@ impl FlashGordon {
         Ok(())
     }
 
-    pub fn clear_page_register(&mut self) {
+    pub fn clear_page_register(&mut self) -> Result {
         let flash = &self.flash.raw;
-        assert!(flash.int_status.read().done().bit_is_set());
+        if flash.int_status.read().done().bit_is_clear() {
+            return Err(Error::Busy);
+        }
         self.clear_status();
 
         for i in 0..32 {
@@ -113,12 +559,13 @@ impl FlashGordon {
             while flash.int_status.read().done().bit_is_clear() {}
             debug_assert!(flash.int_status.read().err().bit_is_clear());
             debug_assert!(flash.int_status.read().fail().bit_is_clear());
-            assert!(self.status().is_ok());
+            self.status()?;
         }
+        Ok(())
     }
 
     pub fn write_u8(&mut self, address: usize, byte: u8) -> Result {
-        self.clear_page_register();
+        self.clear_page_register()?;
         let flash = &self.flash.raw;
         // which "physical word" is this?
         let page_register_column = (address & (512 - 1)) >> 4;
@@ -153,7 +600,7 @@ impl FlashGordon {
     }
 
     pub fn write_u32(&mut self, address: usize, word: u32) -> Result {
-        self.clear_page_register();
+        self.clear_page_register()?;
         let flash = &self.flash.raw;
 
         // which "physical word" is this?
@@ -191,6 +638,9 @@ impl FlashGordon {
         // self.clear_page_register();
 
         let flash = &self.flash.raw;
+        if flash.int_status.read().done().bit_is_clear() {
+            return Err(Error::Busy);
+        }
 
         let buf: [u8; 16] = data.to_ne_bytes();
 
@@ -222,87 +672,134 @@ impl FlashGordon {
         Ok(())
     }
 
-    pub fn read_u128(&mut self, address: usize) -> u128 {
-        let mut buf = [0u8; 16];
-        self.read(address, &mut buf);
-        u128::from_ne_bytes(buf)
+    /// Write `data` starting at `address`, which need not be aligned.
+    ///
+    /// Each page touched is read into a page-sized scratch buffer, patched,
+    /// erased and programmed again. Pages whose content would not change
+    /// are left alone.
+    ///
+    /// This is not power-fail safe: if power is lost after a page is erased
+    /// but before it is programmed again, its previous content is lost.
+    pub fn write_unaligned(&mut self, address: usize, data: &[u8]) -> Result {
+        self.patch(address, data)
     }
-}
 
-impl Read<U16> for FlashGordon {
-    // this reads 16B or one flash word
-    // address is in bytes, whereas starta expects address in flash words
-    // so starta = address / 16 = address >> 4
-    fn read_native(&self, address: usize, array: &mut GenericArray<u8, U16>) {
-        // hprintln!("native read from {} of {:?} (first 16)", address, &array[..16]);
-        let flash = &self.flash.raw;
+    /// Erase the pages `pages.start..pages.end` with a single command.
+    pub fn erase_range(&mut self, pages: Range<usize>) -> Result {
+        self.erase_pages(pages)
+    }
 
-        assert!(flash.int_status.read().done().bit_is_set());
-        self.clear_status();
-        // if self.status().is_err() {
-        //     cortex_m_semihosting::dbg!(flash.int_status.read().bits());
-        //     assert!(self.status().is_ok());
-        // }
-        assert!(self.status().is_ok());
+    /// Start erasing the pages `pages.start..pages.end`, without waiting.
+    ///
+    /// Poll for completion with `wait`. While the controller is busy, flash
+    /// reads (including instruction fetches) stall, so to get useful work
+    /// done in the meantime, that code has to run from RAM. Until then, other
+    /// commands (including `read` and `write`) fail with `Error::Busy`.
+    ///
+    /// Returns `Error::Illegal` if the caller itself runs from these pages.
+    /// This is best-effort only: code it calls, and interrupt handlers, are
+    /// not checked, and must not be placed in the pages either.
+    pub fn start_erase_range(&mut self, pages: Range<usize>) -> Result {
+        self.start_erase_pages(pages)
+    }
 
-        let addr = address as u32;
-        debug_assert!(addr & (READ_SIZE as u32 - 1) == 0);
+    /// Start programming the (erased) page at `address`, without waiting.
+    ///
+    /// Loading the page register is quick and done before returning,
+    /// only the actual programming is left running. Poll for completion with `wait`.
+    ///
+    /// As for `start_erase_range`, the caller must not run from this page.
+    pub fn start_write_page(&mut self, address: usize, page: &GenericArray<u8, U512>) -> Result {
+        self.start_program_page(address, page)
+    }
+}
 
-        flash
-            .starta
-            .write(|w| unsafe { w.starta().bits(addr >> 4) });
-        // want to have normal reads
-        flash.dataw[0].write(|w| unsafe { w.bits(0) });
-        flash
-            .cmd
-            .write(|w| unsafe { w.bits(FlashCommands::ReadSingleWord as u32) });
-        while flash.int_status.read().done().bit_is_clear() {
-            continue;
+impl FlashGordon<Locked> {
+    /// Erase and program access to the pages `pages.start..pages.end`,
+    /// which must not be locked.
+    ///
+    /// A locked handle cannot erase or program by itself.
+    pub fn region(&mut self, pages: Range<usize>) -> Result<FlashRegion<'_>> {
+        if pages.start > pages.end || pages.end > PAGES {
+            return Err(Error::Illegal);
         }
+        self.check_unlocked(pages.clone())?;
+        Ok(FlashRegion { flash: self, pages })
+    }
+}
 
-        assert!(flash.int_status.read().err().bit_is_clear());
-        debug_assert!(flash.int_status.read().fail().bit_is_clear());
+/// Pages of a locked `FlashGordon` that can be erased and programmed, see `FlashGordon::region`.
+///
+/// The pages are checked against the lock once, when the region is created.
+/// Operations on pages outside of the region fail with `Error::WriteProtected`.
+pub struct FlashRegion<'a> {
+    flash: &'a mut FlashGordon<Locked>,
+    pages: Range<usize>,
+}
 
-        // each dataw[i] now contains 4 bytes
-        for (i, chunk) in array.chunks_mut(4).enumerate() {
-            chunk.copy_from_slice(&flash.dataw[i].read().bits().to_ne_bytes());
+impl FlashRegion<'_> {
+    pub fn pages(&self) -> Range<usize> {
+        self.pages.clone()
+    }
+
+    fn check(&self, pages: Range<usize>) -> Result {
+        if pages.is_empty() || (self.pages.start <= pages.start && pages.end <= self.pages.end) {
+            Ok(())
+        } else {
+            Err(Error::WriteProtected)
         }
     }
+
+    // pages touched by `len` bytes at `address`
+    fn check_bytes(&self, address: usize, len: usize) -> Result {
+        self.check(address / PAGE_SIZE..(address + len).div_ceil(PAGE_SIZE))
+    }
+
+    /// See `FlashGordon::write_unaligned`.
+    pub fn write_unaligned(&mut self, address: usize, data: &[u8]) -> Result {
+        self.check_bytes(address, data.len())?;
+        self.flash.patch(address, data)
+    }
+
+    /// See `FlashGordon::erase_range`.
+    pub fn erase_range(&mut self, pages: Range<usize>) -> Result {
+        self.check(pages.clone())?;
+        self.flash.erase_pages(pages)
+    }
+
+    /// See `FlashGordon::start_erase_range`.
+    pub fn start_erase_range(&mut self, pages: Range<usize>) -> Result {
+        self.check(pages.clone())?;
+        self.flash.start_erase_pages(pages)
+    }
+
+    /// See `FlashGordon::start_write_page`.
+    pub fn start_write_page(&mut self, address: usize, page: &GenericArray<u8, U512>) -> Result {
+        self.check_bytes(address, PAGE_SIZE)?;
+        self.flash.start_program_page(address, page)
+    }
+
+    /// See `FlashGordon::wait`.
+    pub fn wait(&mut self) -> nb::Result<(), Error> {
+        self.flash.wait()
+    }
 }
 
-impl WriteErase<U512, U512> for FlashGordon {
+impl Read<U16> for FlashRegion<'_> {
+    fn read_native(&self, address: usize, array: &mut GenericArray<u8, U16>) -> Result<EccStatus> {
+        self.flash.read_word(address, array)
+    }
+}
+
+impl WriteErase<U512, U512> for FlashGordon<Unlocked> {
     fn status(&self) -> Result {
         self.status()
     }
 
     // TODO: use critical section?
     fn erase_page(&mut self, page: usize) -> Result {
-        // starta is still in flash words, of which a page has 32
-        let starta = page * 32;
         // hprintln!("native erase page {}", page);
-
-        let flash = &self.flash.raw;
-        assert!(flash.int_status.read().done().bit_is_set());
-        self.clear_status();
-        assert!(flash.int_status.read().done().bit_is_clear());
-
-        flash
-            .starta
-            .write(|w| unsafe { w.starta().bits(starta as u32) });
-        flash
-            .stopa
-            .write(|w| unsafe { w.stopa().bits(starta as u32) });
-        flash
-            .cmd
-            .write(|w| unsafe { w.bits(FlashCommands::EraseRange as u32) });
-        while flash.int_status.read().done().bit_is_clear() {}
-
-        debug_assert!(flash.int_status.read().err().bit_is_clear());
-        debug_assert!(flash.int_status.read().fail().bit_is_clear());
-        // cortex_m_semihosting::dbg!(self.status());
-        self.status()?;
-
-        Ok(())
+        self.erase_pages(page..page + 1)
     }
 
     fn write_native(
@@ -311,50 +808,35 @@ impl WriteErase<U512, U512> for FlashGordon {
         array: &GenericArray<u8, U512>,
         // cs: &CriticalSection,
     ) -> Result {
-        // hprintln!("native write to {} of {:?} (first 16)", address, &array[..16]);
-        let flash = &self.flash.raw;
-        assert!(flash.int_status.read().done().bit_is_set());
-        self.clear_status();
+        self.program_page(address, array)
+    }
+}
 
-        // maybe check the page is erased?
+impl WriteErase<U512, U512> for FlashRegion<'_> {
+    fn status(&self) -> Result {
+        self.flash.status()
+    }
 
-        // write one physical word (16 bytes) at a time
-        for (i, chunk) in array.chunks(16).enumerate() {
-            let starta = (address >> 4) + i;
-            flash
-                .starta
-                .write(|w| unsafe { w.starta().bits(starta as u32) });
+    fn erase_page(&mut self, page: usize) -> Result {
+        self.erase_range(page..page + 1)
+    }
 
-            for (j, word) in chunk.chunks(4).enumerate() {
-                flash.dataw[j]
-                    .write(|w| unsafe { w.bits(u32::from_ne_bytes(word.try_into().unwrap())) });
-            }
+    fn write_native(&mut self, address: usize, array: &GenericArray<u8, U512>) -> Result {
+        self.check_bytes(address, PAGE_SIZE)?;
+        self.flash.program_page(address, array)
+    }
+}
 
-            flash
-                .cmd
-                .write(|w| unsafe { w.bits(FlashCommands::Write as u32) });
-            // flash.cmd.write(|w| unsafe { w.bits(FlashCommands::WriteProgram as u32) });
-            while flash.int_status.read().done().bit_is_clear() {}
-            debug_assert!(flash.int_status.read().err().bit_is_clear());
-            debug_assert!(flash.int_status.read().fail().bit_is_clear());
-            self.status()?;
+/// CRC-32 (IEEE 802.3), as used for integrity checks of records in flash.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
         }
-        self.clear_status();
-
-        let starta = address >> 4;
-        flash
-            .starta
-            .write(|w| unsafe { w.starta().bits(starta as u32) });
-        flash
-            .cmd
-            .write(|w| unsafe { w.bits(FlashCommands::Program as u32) });
-        while flash.int_status.read().done().bit_is_clear() {}
-        debug_assert!(flash.int_status.read().err().bit_is_clear());
-        debug_assert!(flash.int_status.read().fail().bit_is_clear());
-        self.status()?;
-
-        Ok(())
     }
+    !crc
 }
 
 #[allow(dead_code)]
@@ -383,31 +865,62 @@ pub mod littlefs_params {
     pub const WRITE_SIZE: usize = 512;
     pub const BLOCK_SIZE: usize = 512;
 
-    // no wear-leveling for now
-    pub const BLOCK_CYCLES: isize = -1;
+    // erase cycles before littlefs moves metadata to another block,
+    // littlefs suggests 100-1000
+    pub const BLOCK_CYCLES: isize = 500;
 
     pub type CACHE_SIZE = U512;
     pub type LOOKAHEAD_SIZE = U8;
 }
 
+/// Implement `littlefs2::driver::Storage` for a region of internal flash.
+///
+/// ```ignore
+/// // from 0x8_0000 to the end of usable flash (631.5KB)
+/// littlefs2_filesystem!(Filesystem: (0x8_0000));
+/// // 10 blocks from 0x8_0000
+/// littlefs2_filesystem!(Filesystem: (0x8_0000, 10));
+/// // overriding the defaults in `littlefs_params`
+/// littlefs2_filesystem!(Filesystem: (0x8_0000, 10) {
+///     block_cycles: 100,
+///     cache_size: U256,
+///     lookahead_size: U4,
+/// });
+/// ```
 #[cfg(feature = "littlefs")]
 #[macro_export]
 macro_rules! littlefs2_filesystem {
     ($Name:ident: (
         $BASE_OFFSET:expr
-    )) => {
-        littlefs2_filesystem!(
+    ) $({ $($config:tt)* })?) => {
+        $crate::littlefs2_filesystem!(
             $Name: (
                 $BASE_OFFSET,
                 //     631.5KB
                 ((631 * 1024 + 512) - $BASE_OFFSET) / 512
-            )
+            ) $({ $($config)* })?
         );
     };
     ($Name:ident: (
         $BASE_OFFSET:expr,
         $BLOCK_COUNT:expr
     )) => {
+        $crate::littlefs2_filesystem!(
+            $Name: ($BASE_OFFSET, $BLOCK_COUNT) {
+                block_cycles: $crate::drivers::flash::littlefs_params::BLOCK_CYCLES,
+                cache_size: $crate::drivers::flash::littlefs_params::CACHE_SIZE,
+                lookahead_size: $crate::drivers::flash::littlefs_params::LOOKAHEAD_SIZE,
+            }
+        );
+    };
+    ($Name:ident: (
+        $BASE_OFFSET:expr,
+        $BLOCK_COUNT:expr
+    ) {
+        block_cycles: $BLOCK_CYCLES:expr,
+        cache_size: $CACHE_SIZE:ty,
+        lookahead_size: $LOOKAHEAD_SIZE:ty $(,)?
+    }) => {
         //
         // Compile time assertion that $BASE_OFFSET is 512 byte aligned.
         const _ZERO_SIZED_CHECK: usize = ((core::mem::size_of::<[u8; ($BASE_OFFSET % 512)]>() == 0) as usize) - 1;
@@ -433,15 +946,19 @@ macro_rules! littlefs2_filesystem {
             const BLOCK_SIZE: usize = $crate::drivers::flash::littlefs_params::BLOCK_SIZE;
 
             const BLOCK_COUNT: usize = $BLOCK_COUNT;
-            const BLOCK_CYCLES: isize = $crate::drivers::flash::littlefs_params::BLOCK_CYCLES;
+            const BLOCK_CYCLES: isize = $BLOCK_CYCLES;
 
-            type CACHE_SIZE = $crate::drivers::flash::littlefs_params::CACHE_SIZE;
-            type LOOKAHEAD_SIZE = $crate::drivers::flash::littlefs_params::LOOKAHEAD_SIZE;
+            type CACHE_SIZE = $CACHE_SIZE;
+            type LOOKAHEAD_SIZE = $LOOKAHEAD_SIZE;
 
 
             fn read(&mut self, off: usize, buf: &mut [u8]) -> littlefs2::io::Result<usize> {
                 <$crate::drivers::flash::FlashGordon as $crate::traits::flash::Read<$crate::drivers::flash::U16>>
-                    ::read(&self.flash_gordon, Self::BASE_OFFSET + off, buf);
+                    ::read(&self.flash_gordon, Self::BASE_OFFSET + off, buf)
+                    .map_err(|error| match error {
+                        $crate::traits::flash::Error::EccError => littlefs2::io::Error::CORRUPTION,
+                        _ => littlefs2::io::Error::IO,
+                    })?;
                 Ok(buf.len())
             }
 
@@ -456,12 +973,10 @@ macro_rules! littlefs2_filesystem {
             fn erase(&mut self, off: usize, len: usize) -> littlefs2::io::Result<usize> {
                 let first_page = (Self::BASE_OFFSET + off) / 512;
                 let pages = len / 512;
-                for i in 0..pages {
-                    <$crate::drivers::flash::FlashGordon as $crate::traits::flash::WriteErase<$crate::drivers::flash::U512, $crate::drivers::flash::U512>>
-                        ::erase_page(&mut self.flash_gordon, first_page + i)
-                        .map_err(|_| littlefs2::io::Error::IO)?;
-                }
-                Ok(512 * len)
+                self.flash_gordon
+                    .erase_range(first_page..first_page + pages)
+                    .map_err(|_| littlefs2::io::Error::IO)?;
+                Ok(len)
             }
 
         }
@@ -469,30 +984,56 @@ macro_rules! littlefs2_filesystem {
     }
 }
 
+/// Like `littlefs2_filesystem!`, but with the flash region PRINCE-encrypted.
+///
+/// The PRINCE region (`Region0`: 0-256KB, `Region1`: 256-512KB,
+/// `Region2`: 512KB up) is determined by the base offset,
+/// the filesystem must not span several regions.
 #[cfg(feature = "littlefs")]
 #[macro_export]
 macro_rules! littlefs2_prince_filesystem {
     ($Name:ident: (
         $BASE_OFFSET:expr
-    )) => {
-        littlefs2_prince_filesystem!(
+    ) $({ $($config:tt)* })?) => {
+        $crate::littlefs2_prince_filesystem!(
             $Name: (
                 $BASE_OFFSET,
                 //     631.5KB
                 ((631 * 1024 + 512) - $BASE_OFFSET) / 512
-            )
+            ) $({ $($config)* })?
         );
     };
     ($Name:ident: (
         $BASE_OFFSET:expr,
         $BLOCK_COUNT:expr
     )) => {
+        $crate::littlefs2_prince_filesystem!(
+            $Name: ($BASE_OFFSET, $BLOCK_COUNT) {
+                block_cycles: $crate::drivers::flash::littlefs_params::BLOCK_CYCLES,
+                cache_size: $crate::drivers::flash::littlefs_params::CACHE_SIZE,
+                lookahead_size: $crate::drivers::flash::littlefs_params::LOOKAHEAD_SIZE,
+            }
+        );
+    };
+    ($Name:ident: (
+        $BASE_OFFSET:expr,
+        $BLOCK_COUNT:expr
+    ) {
+        block_cycles: $BLOCK_CYCLES:expr,
+        cache_size: $CACHE_SIZE:ty,
+        lookahead_size: $LOOKAHEAD_SIZE:ty $(,)?
+    }) => {
         //
         // Compile time assertion that $BASE_OFFSET is 512 byte aligned.
         const _ZERO_SIZED_CHECK_0: usize = ((core::mem::size_of::<[u8; ($BASE_OFFSET % 512)]>() == 0) as usize) - 1;
         // Compile time assertion that flash region does NOT spill over the 631.5KB boundary.
         const _OVERFLOW_SIZE_CHECK_0: usize = ((
             core::mem::size_of::<[u8; (($BASE_OFFSET + $BLOCK_COUNT * 512) <= (631 * 1024 + 512)) as usize]>() == 1) as usize) - 1;
+        // Compile time assertion that flash region is within one PRINCE region.
+        const _PRINCE_REGION_CHECK_0: () = assert!(
+            $crate::peripherals::prince::Region::containing($BASE_OFFSET) as usize
+                == $crate::peripherals::prince::Region::containing($BASE_OFFSET + $BLOCK_COUNT * 512 - 1) as usize
+        );
 
 
         pub struct $Name {
@@ -502,6 +1043,8 @@ macro_rules! littlefs2_prince_filesystem {
 
         impl $Name {
             const BASE_OFFSET: usize = $BASE_OFFSET;
+            const REGION: $crate::peripherals::prince::Region =
+                $crate::peripherals::prince::Region::containing($BASE_OFFSET);
 
             pub fn new (
                 flash_gordon: $crate::drivers::flash::FlashGordon,
@@ -517,14 +1060,14 @@ macro_rules! littlefs2_prince_filesystem {
             const BLOCK_SIZE: usize = $crate::drivers::flash::littlefs_params::BLOCK_SIZE;
 
             const BLOCK_COUNT: usize = $BLOCK_COUNT;
-            const BLOCK_CYCLES: isize = $crate::drivers::flash::littlefs_params::BLOCK_CYCLES;
+            const BLOCK_CYCLES: isize = $BLOCK_CYCLES;
 
-            type CACHE_SIZE = $crate::drivers::flash::littlefs_params::CACHE_SIZE;
-            type LOOKAHEAD_SIZE = $crate::drivers::flash::littlefs_params::LOOKAHEAD_SIZE;
+            type CACHE_SIZE = $CACHE_SIZE;
+            type LOOKAHEAD_SIZE = $LOOKAHEAD_SIZE;
 
 
             fn read(&mut self, off: usize, buf: &mut [u8]) -> littlefs2::io::Result<usize> {
-                self.prince.enable_region_2_for(||{
+                self.prince.enable_region_for(Self::REGION, ||{
                     let flash: *const u8 = (Self::BASE_OFFSET + off) as *const u8;
                     for i in 0 .. buf.len() {
                         buf[i] = unsafe{ *flash.offset(i as isize) };
@@ -537,7 +1080,7 @@ macro_rules! littlefs2_prince_filesystem {
                 let prince = &mut self.prince;
                 let flash_gordon = &mut self.flash_gordon;
                 let ret = prince.write_encrypted(|prince| {
-                    prince.enable_region_2_for(||{
+                    prince.enable_region_for(Self::REGION, ||{
                         <$crate::drivers::flash::FlashGordon as
                             $crate::traits::flash::WriteErase<$crate::drivers::flash::U512, $crate::drivers::flash::U512>>
                             ::write(flash_gordon, Self::BASE_OFFSET + off, data)
@@ -551,13 +1094,10 @@ macro_rules! littlefs2_prince_filesystem {
             fn erase(&mut self, off: usize, len: usize) -> littlefs2::io::Result<usize> {
                 let first_page = (Self::BASE_OFFSET + off) / 512;
                 let pages = len / 512;
-                for i in 0..pages {
-                    <$crate::drivers::flash::FlashGordon as
-                        $crate::traits::flash::WriteErase<$crate::drivers::flash::U512, $crate::drivers::flash::U512>>
-                        ::erase_page(&mut self.flash_gordon, first_page + i)
-                        .map_err(|_| littlefs2::io::Error::IO)?;
-                }
-                Ok(512 * len)
+                self.flash_gordon
+                    .erase_range(first_page..first_page + pages)
+                    .map_err(|_| littlefs2::io::Error::IO)?;
+                Ok(len)
             }
 
         }
@@ -0,0 +1,628 @@
+//! Log-structured key-value store.
+//!
+//! Flash pages cannot be programmed twice without erasing them in between,
+//! so each record occupies one page: a 16 byte header followed by the value.
+//! New records go to the next free page in round-robin order, which spreads
+//! wear over all pages of the store. Of several records for the same key,
+//! the one with the highest sequence number wins; deletions are recorded as
+//! tombstones.
+//!
+//! The state of each page and the latest record of each key are indexed
+//! in RAM when mounting, so that lookups and writes need not scan flash.
+//!
+//! A record is only superseded once its replacement is completely written,
+//! and torn writes fail their CRC check and are ignored, so the store
+//! survives power loss at any point.
+
+use core::{cmp::min, convert::TryInto};
+
+use super::{crc32, GenericArray, PAGE_SIZE, READ_SIZE, U16, U512};
+use crate::traits::flash::{self, Read, WriteErase};
+
+const MAGIC: u32 = 0x4b56_5354;
+// crc, magic, seq, key, len
+const HEADER_SIZE: usize = READ_SIZE;
+const TOMBSTONE: u16 = 0xffff;
+
+/// Largest value that can be stored.
+pub const MAX_VALUE_LEN: usize = PAGE_SIZE - HEADER_SIZE;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Flash(flash::Error),
+    /// Store is not page aligned, or has less than two pages
+    Misconfigured,
+    /// Value is larger than `MAX_VALUE_LEN`
+    ValueTooLarge,
+    /// Buffer passed to `get` cannot hold the value
+    BufferTooSmall,
+    /// No free page left, even after garbage collection
+    Full,
+}
+
+impl From<flash::Error> for Error {
+    fn from(error: flash::Error) -> Self {
+        Error::Flash(error)
+    }
+}
+
+pub type Result<T = ()> = core::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Header {
+    seq: u32,
+    key: u16,
+    len: u16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Page {
+    Free,
+    Record(Header),
+    /// Torn write or foreign data
+    Garbage,
+}
+
+// latest record of a key
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Entry {
+    key: u16,
+    index: usize,
+    seq: u32,
+    len: u16,
+    // records of the key still in flash, including the latest
+    records: usize,
+}
+
+/// Key-value store over `PAGES` pages of flash.
+///
+/// The state of all pages, and the latest record of each key, are read
+/// once by `mount` and kept in RAM, so `get`, `set` and `remove` only
+/// access the pages they read, erase or program.
+pub struct KvStore<F, const PAGES: usize> {
+    flash: F,
+    first_page: usize,
+    pages: [Page; PAGES],
+    // garbage, superseded records, and tombstones hiding nothing
+    reclaimable: [bool; PAGES],
+    // at most one key per page
+    entries: [Option<Entry>; PAGES],
+    // page to try first for the next record
+    next: usize,
+    seq: u32,
+}
+
+impl<F, const PAGES: usize> KvStore<F, PAGES>
+where
+    F: Read<U16> + WriteErase<U512, U512>,
+{
+    /// Use the `PAGES` pages starting at byte offset `address` as store.
+    ///
+    /// Existing records are kept, pages in an unknown state are
+    /// reclaimed when needed.
+    pub fn mount(flash: F, address: usize) -> Result<Self> {
+        if address % PAGE_SIZE != 0 || PAGES < 2 {
+            return Err(Error::Misconfigured);
+        }
+        let mut store = KvStore {
+            flash,
+            first_page: address / PAGE_SIZE,
+            pages: [Page::Free; PAGES],
+            reclaimable: [false; PAGES],
+            entries: [None; PAGES],
+            next: 0,
+            seq: 0,
+        };
+        for index in 0..PAGES {
+            let page = store.page(index)?;
+            store.pages[index] = page;
+            match page {
+                Page::Record(header) => {
+                    if header.seq >= store.seq {
+                        store.seq = header.seq;
+                        store.next = (index + 1) % PAGES;
+                    }
+                    store.insert(index, header);
+                }
+                Page::Garbage => store.reclaimable[index] = true,
+                Page::Free => {}
+            }
+        }
+        for index in 0..PAGES {
+            if let Page::Record(header) = store.pages[index] {
+                store.reclaimable[index] = match store.entry(header.key) {
+                    Some(entry) => Self::is_reclaimable(entry, index),
+                    None => true,
+                };
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Read the value of `key` into `buf`, returning its length,
+    /// or `None` if there is no such key.
+    pub fn get(&self, key: u16, buf: &mut [u8]) -> Result<Option<usize>> {
+        let entry = match self.entry(key) {
+            Some(entry) if entry.len != TOMBSTONE => *entry,
+            _ => return Ok(None),
+        };
+        let len = entry.len as usize;
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut page = GenericArray::<u8, U512>::default();
+        self.flash.read(self.address(entry.index), &mut page)?;
+        buf[..len].copy_from_slice(&page[HEADER_SIZE..][..len]);
+        Ok(Some(len))
+    }
+
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLarge);
+        }
+        self.append(key, value.len() as u16, value)
+    }
+
+    /// Remove `key`, if present.
+    pub fn remove(&mut self, key: u16) -> Result {
+        match self.entry(key) {
+            Some(entry) if entry.len != TOMBSTONE => self.append(key, TOMBSTONE, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    /// Erase all pages whose records are no longer needed.
+    ///
+    /// This is done on demand by `set` and `remove` anyway; calling it
+    /// in idle time makes them faster.
+    pub fn gc(&mut self) -> Result {
+        for index in 0..PAGES {
+            if self.reclaimable[index] {
+                self.erase(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, key: u16, len: u16, value: &[u8]) -> Result {
+        let index = self.allocate()?;
+        let seq = self.seq.wrapping_add(1);
+
+        let mut page = GenericArray::<u8, U512>::default();
+        page[4..8].copy_from_slice(&MAGIC.to_le_bytes());
+        page[8..12].copy_from_slice(&seq.to_le_bytes());
+        page[12..14].copy_from_slice(&key.to_le_bytes());
+        page[14..16].copy_from_slice(&len.to_le_bytes());
+        page[HEADER_SIZE..][..value.len()].copy_from_slice(value);
+        let crc = Self::crc(&page);
+        page[..4].copy_from_slice(&crc.to_le_bytes());
+
+        // until written, a page is not free anymore
+        self.pages[index] = Page::Garbage;
+        self.reclaimable[index] = true;
+        self.flash.write_native(self.address(index), &page)?;
+        self.seq = seq;
+        self.next = (index + 1) % PAGES;
+
+        let header = Header { seq, key, len };
+        self.pages[index] = Page::Record(header);
+        self.reclaimable[index] = false;
+        if let Some(previous) = self.entry(key).map(|entry| entry.index) {
+            self.reclaimable[previous] = true;
+        }
+        self.insert(index, header);
+        Ok(())
+    }
+
+    // find the next free page, reclaiming one if necessary
+    fn allocate(&mut self) -> Result<usize> {
+        for offset in 0..PAGES {
+            let index = (self.next + offset) % PAGES;
+            if self.pages[index] == Page::Free {
+                return Ok(index);
+            }
+            if self.reclaimable[index] {
+                self.erase(index)?;
+                return Ok(index);
+            }
+        }
+        Err(Error::Full)
+    }
+
+    fn erase(&mut self, index: usize) -> Result {
+        let page = self.pages[index];
+        // until erased, a page is garbage
+        self.pages[index] = Page::Garbage;
+        self.reclaimable[index] = true;
+        if let Page::Record(header) = page {
+            self.forget(index, header);
+        }
+        self.flash.erase_page(self.first_page + index)?;
+        self.pages[index] = Page::Free;
+        self.reclaimable[index] = false;
+        Ok(())
+    }
+
+    // a record is needed if it is the latest for its key, and either holds
+    // a value, or is a tombstone hiding older records
+    fn is_reclaimable(entry: &Entry, index: usize) -> bool {
+        entry.index != index || (entry.len == TOMBSTONE && entry.records == 1)
+    }
+
+    fn entry(&self, key: u16) -> Option<&Entry> {
+        self.entries.iter().flatten().find(|entry| entry.key == key)
+    }
+
+    // account for the record of `header` at page `index`
+    fn insert(&mut self, index: usize, header: Header) {
+        let latest = Entry {
+            key: header.key,
+            index,
+            seq: header.seq,
+            len: header.len,
+            records: 1,
+        };
+        let mut free = None;
+        for slot in self.entries.iter_mut() {
+            match slot {
+                Some(entry) if entry.key == header.key => {
+                    let records = entry.records + 1;
+                    if header.seq > entry.seq {
+                        *entry = latest;
+                    }
+                    entry.records = records;
+                    return;
+                }
+                None if free.is_none() => free = Some(slot),
+                _ => {}
+            }
+        }
+        // there are never more keys than pages
+        if let Some(slot) = free {
+            *slot = Some(latest);
+        }
+    }
+
+    // account for the erasure of the record of `header` at page `index`
+    fn forget(&mut self, index: usize, header: Header) {
+        let slot = match self
+            .entries
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(entry) if entry.key == header.key))
+        {
+            Some(slot) => slot,
+            None => return,
+        };
+        let entry = slot.as_mut().unwrap();
+        entry.records -= 1;
+        if entry.records == 0 || entry.index == index {
+            // only reclaimable latest records are lone tombstones
+            *slot = None;
+        } else if entry.len == TOMBSTONE && entry.records == 1 {
+            self.reclaimable[entry.index] = true;
+        }
+    }
+
+    // pages with uncorrectable ECC errors are garbage
+    fn page(&self, index: usize) -> Result<Page> {
+        match self.classify(index) {
+            Err(flash::Error::EccError) => Ok(Page::Garbage),
+            result => Ok(result?),
+        }
+    }
+
+    fn classify(&self, index: usize) -> flash::Result<Page> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        self.flash.read(self.address(index), &mut bytes)?;
+        if bytes.iter().all(|&byte| byte == 0xff) {
+            // an erased header does not guarantee an erased page
+            let mut page = GenericArray::<u8, U512>::default();
+            self.flash.read(self.address(index), &mut page)?;
+            return match page.iter().all(|&byte| byte == 0xff) {
+                true => Ok(Page::Free),
+                false => Ok(Page::Garbage),
+            };
+        }
+
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let half = |i: usize| u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap());
+        let header = Header {
+            seq: word(8),
+            key: half(12),
+            len: half(14),
+        };
+        if word(4) != MAGIC || (header.len != TOMBSTONE && header.len as usize > MAX_VALUE_LEN) {
+            return Ok(Page::Garbage);
+        }
+
+        let mut page = GenericArray::<u8, U512>::default();
+        self.flash.read(self.address(index), &mut page)?;
+        match Self::crc(&page) == word(0) {
+            true => Ok(Page::Record(header)),
+            false => Ok(Page::Garbage),
+        }
+    }
+
+    // over the rest of the header and the value
+    fn crc(page: &[u8]) -> u32 {
+        let len = match u16::from_le_bytes(page[14..16].try_into().unwrap()) {
+            TOMBSTONE => 0,
+            len => min(len as usize, MAX_VALUE_LEN),
+        };
+        crc32(&page[4..HEADER_SIZE + len])
+    }
+
+    fn address(&self, index: usize) -> usize {
+        (self.first_page + index) * PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::flash::{SimFlash, PAGE_WORDS};
+    use crate::traits::flash::EccStatus;
+    use core::cell::Cell;
+
+    type Flash = SimFlash<6>;
+    // leaves the first page of the flash alone
+    type Store = KvStore<Flash, 4>;
+
+    fn mount(flash: Flash) -> Store {
+        KvStore::mount(flash, PAGE_SIZE).unwrap()
+    }
+
+    fn get(store: &Store, key: u16) -> Option<([u8; MAX_VALUE_LEN], usize)> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        store.get(key, &mut buf).unwrap().map(|len| (buf, len))
+    }
+
+    fn value(store: &Store, key: u16) -> Option<u8> {
+        get(store, key).map(|(buf, len)| {
+            assert_eq!(len, 3);
+            buf[0]
+        })
+    }
+
+    #[test]
+    fn misconfigured() {
+        assert!(matches!(
+            KvStore::<_, 4>::mount(Flash::new(), 16),
+            Err(Error::Misconfigured)
+        ));
+        assert!(matches!(
+            KvStore::<_, 1>::mount(Flash::new(), 0),
+            Err(Error::Misconfigured)
+        ));
+    }
+
+    #[test]
+    fn set_get_overwrite() {
+        let mut store = mount(Flash::new());
+        assert_eq!(value(&store, 1), None);
+
+        store.set(1, &[1; 3]).unwrap();
+        store.set(2, &[2; 3]).unwrap();
+        assert_eq!(value(&store, 1), Some(1));
+        assert_eq!(value(&store, 2), Some(2));
+
+        store.set(1, &[3; 3]).unwrap();
+        assert_eq!(value(&store, 1), Some(3));
+
+        store.set(3, &[7; MAX_VALUE_LEN]).unwrap();
+        let (buf, len) = get(&store, 3).unwrap();
+        assert_eq!(buf[..len], [7; MAX_VALUE_LEN]);
+        assert_eq!(
+            store.set(3, &[0; MAX_VALUE_LEN + 1]),
+            Err(Error::ValueTooLarge)
+        );
+        assert_eq!(store.get(3, &mut [0; 16]), Err(Error::BufferTooSmall));
+
+        // and after remounting
+        let store = mount(store.release());
+        assert_eq!(value(&store, 1), Some(3));
+        assert_eq!(value(&store, 2), Some(2));
+        // first page untouched
+        assert!(store.release().page(0).iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn remove() {
+        let mut store = mount(Flash::new());
+        store.set(1, &[1; 3]).unwrap();
+        store.set(2, &[2; 3]).unwrap();
+        store.remove(1).unwrap();
+        assert_eq!(value(&store, 1), None);
+        assert_eq!(value(&store, 2), Some(2));
+        // removing twice writes no second tombstone
+        store.remove(1).unwrap();
+        store.remove(5).unwrap();
+        assert_eq!(store.seq, 3);
+
+        let mut store = mount(store.release());
+        assert_eq!(value(&store, 1), None);
+        store.set(1, &[4; 3]).unwrap();
+        assert_eq!(value(&store, 1), Some(4));
+    }
+
+    #[test]
+    fn gc_and_full() {
+        let mut store = mount(Flash::new());
+        for key in 0..4 {
+            store.set(key, &[key as u8; 3]).unwrap();
+        }
+        // all pages hold needed records
+        assert_eq!(store.set(4, &[4; 3]), Err(Error::Full));
+        // overwriting needs a free page, too
+        assert_eq!(store.set(0, &[5; 3]), Err(Error::Full));
+
+        store.remove(0).unwrap_err();
+        assert_eq!(value(&store, 0), Some(0));
+        let store = mount(store.release());
+        for key in 0..4 {
+            assert_eq!(value(&store, key), Some(key as u8));
+        }
+
+        // after freeing a page, records can be overwritten indefinitely
+        let mut flash = store.release();
+        flash.erase_page(1 + 3).unwrap();
+        let mut store = mount(flash);
+        assert_eq!(value(&store, 3), None);
+        for round in 0..20 {
+            store.set(0, &[round; 3]).unwrap();
+            assert_eq!(value(&store, 0), Some(round));
+        }
+        for key in 1..3 {
+            assert_eq!(value(&store, key), Some(key as u8));
+        }
+
+        // a tombstone hiding nothing anymore is reclaimed
+        store.remove(1).unwrap();
+        store.gc().unwrap();
+        let free = store
+            .pages
+            .iter()
+            .filter(|&&page| page == Page::Free)
+            .count();
+        assert_eq!(free, 2);
+        let store = mount(store.release());
+        assert_eq!(value(&store, 1), None);
+        assert_eq!(value(&store, 0), Some(19));
+    }
+
+    #[test]
+    fn torn_write_keeps_previous_value() {
+        let mut store = mount(Flash::new());
+        store.set(1, &[1; 3]).unwrap();
+
+        let mut flash = store.release();
+        flash.power_loss_after(1);
+        let mut store = mount(flash);
+        assert!(store.set(1, &[2; 3]).is_err());
+
+        let mut flash = store.release();
+        flash.power_cycle();
+        let mut store = mount(flash);
+        assert_eq!(value(&store, 1), Some(1));
+        // the torn page is reclaimed
+        for round in 0..8 {
+            store.set(1, &[round; 3]).unwrap();
+        }
+        assert_eq!(value(&store, 1), Some(7));
+    }
+
+    #[test]
+    fn power_loss_at_every_word() {
+        type State = [Option<u8>; 2];
+        // the state after each operation
+        const STATES: [State; 4] = [
+            [Some(1), Some(2)],
+            [Some(3), Some(2)],
+            [Some(3), None],
+            [Some(3), Some(4)],
+        ];
+        fn run(store: &mut Store, done: &mut usize) -> Result {
+            store.set(1, &[3; 3])?;
+            *done += 1;
+            store.remove(2)?;
+            *done += 1;
+            store.set(2, &[4; 3])?;
+            *done += 1;
+            Ok(())
+        }
+
+        for words in 0.. {
+            let mut store = mount(Flash::new());
+            // all pages used, two of them reclaimable
+            for (key, byte) in [(1, 0), (2, 2), (1, 0), (1, 1)] {
+                store.set(key, &[byte; 3]).unwrap();
+            }
+
+            let mut flash = store.release();
+            flash.power_loss_after(words);
+            let mut store = mount(flash);
+            let mut done = 0;
+            let result = run(&mut store, &mut done);
+            let mut flash = store.release();
+            if flash.is_powered() {
+                assert_eq!(result, Ok(()));
+                assert!(words > 3 * PAGE_WORDS);
+                break;
+            }
+            flash.power_cycle();
+
+            let mut store = mount(flash);
+            let state = [value(&store, 1), value(&store, 2)];
+            assert!(
+                state == STATES[done] || state == STATES[done + 1],
+                "{:?} after {} operations, power loss after {} words",
+                state,
+                done,
+                words
+            );
+            // the store is still usable
+            store.set(3, &[5; 3]).unwrap();
+            store.set(1, &[6; 3]).unwrap();
+            assert_eq!(value(&store, 1), Some(6));
+            assert_eq!(value(&store, 3), Some(5));
+        }
+    }
+
+    // counts native reads
+    struct Counting {
+        flash: Flash,
+        reads: Cell<usize>,
+    }
+
+    impl Read<U16> for Counting {
+        fn read_native(
+            &self,
+            address: usize,
+            array: &mut GenericArray<u8, U16>,
+        ) -> flash::Result<EccStatus> {
+            self.reads.set(self.reads.get() + 1);
+            self.flash.read_native(address, array)
+        }
+    }
+
+    impl WriteErase<U512, U512> for Counting {
+        fn status(&self) -> flash::Result {
+            self.flash.status()
+        }
+
+        fn erase_page(&mut self, page: usize) -> flash::Result {
+            self.flash.erase_page(page)
+        }
+
+        fn write_native(
+            &mut self,
+            address: usize,
+            array: &GenericArray<u8, U512>,
+        ) -> flash::Result {
+            self.flash.write_native(address, array)
+        }
+    }
+
+    #[test]
+    fn no_scans_after_mount() {
+        let flash = Counting {
+            flash: Flash::new(),
+            reads: Cell::new(0),
+        };
+        let mut store = KvStore::<_, 4>::mount(flash, PAGE_SIZE).unwrap();
+        let mut buf = [0u8; 3];
+        for round in 0..20 {
+            store.flash.reads.set(0);
+            store.set(round % 3, &[round as u8; 3]).unwrap();
+            store.remove(5).unwrap();
+            assert_eq!(store.flash.reads.get(), 0);
+            store.get(round % 3, &mut buf).unwrap();
+            // a single page
+            assert_eq!(store.flash.reads.get(), PAGE_SIZE / READ_SIZE);
+        }
+    }
+}
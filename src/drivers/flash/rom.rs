@@ -0,0 +1,271 @@
+//! Flash access through the boot ROM flash driver.
+//!
+//! `RomFlash` implements the same traits as `FlashGordon`, but erases and
+//! programs using the vendor's ROM routines, verifying each operation.
+//! This is slower, but a useful fallback and cross-check.
+//!
+//! The ROM offers no way to learn about corrected ECC errors, so reads always
+//! report `EccStatus::Clean`; an uncorrectable error faults the bus.
+
+use core::cmp::min;
+
+use super::{GenericArray, PAGE_SIZE, READ_SIZE, U16, U512};
+use crate::{
+    peripherals::pfr::Pfr,
+    traits::flash::{EccStatus, Error, Read, Result, WriteErase},
+    typestates::init_state::Enabled,
+};
+
+// 'kefl', the ROM refuses to erase without it
+const ERASE_KEY: u32 = 0x6b65_666c;
+
+// status codes of the ROM flash driver
+const STATUS_INVALID_ARGUMENT: u32 = 4;
+const STATUS_SIZE_ERROR: u32 = 100;
+const STATUS_ALIGNMENT_ERROR: u32 = 101;
+const STATUS_ADDRESS_ERROR: u32 = 102;
+const STATUS_PROTECTION_VIOLATION: u32 = 104;
+const STATUS_ECC_ERROR: u32 = 116;
+const STATUS_COMPARE_ERROR: u32 = 117;
+
+/// Failure of a ROM flash driver call
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RomError {
+    /// Status code returned by the ROM
+    Status(u32),
+    /// Programmed data does not match, at the first mismatching (32-bit) word
+    VerifyFailed { address: u32, data: u32 },
+}
+
+impl From<RomError> for Error {
+    fn from(error: RomError) -> Self {
+        match error {
+            RomError::Status(
+                STATUS_INVALID_ARGUMENT
+                | STATUS_SIZE_ERROR
+                | STATUS_ALIGNMENT_ERROR
+                | STATUS_ADDRESS_ERROR,
+            ) => Error::Illegal,
+            RomError::Status(STATUS_PROTECTION_VIOLATION) => Error::WriteProtected,
+            RomError::Status(STATUS_ECC_ERROR) => Error::EccError,
+            _ => Error::Failure,
+        }
+    }
+}
+
+/// Properties reported by `RomFlash::property`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Property {
+    SectorSize = 0x00,
+    TotalSize = 0x01,
+    BlockSize = 0x02,
+    BlockCount = 0x03,
+    BlockBaseAddress = 0x04,
+    PageSize = 0x30,
+    SystemFrequency = 0x31,
+    FfrSectorSize = 0x40,
+    FfrTotalSize = 0x41,
+    FfrBlockBaseAddress = 0x42,
+    FfrPageSize = 0x43,
+}
+
+pub struct RomFlash {
+    pfr: Pfr<Enabled>,
+    last_error: Option<RomError>,
+}
+
+impl RomFlash {
+    pub fn new(pfr: Pfr<Enabled>) -> Self {
+        RomFlash {
+            pfr,
+            last_error: None,
+        }
+    }
+
+    pub fn release(self) -> Pfr<Enabled> {
+        self.pfr
+    }
+
+    /// Details of the last failure reported via the flash traits.
+    pub fn last_error(&self) -> Option<RomError> {
+        self.last_error
+    }
+
+    /// Erase `len` bytes (whole pages) at `address`.
+    pub fn erase(&mut self, address: usize, len: usize) -> core::result::Result<(), RomError> {
+        let flash_erase = Pfr::<Enabled>::bootloader_api_tree()
+            .flash_driver
+            .flash_erase;
+        Self::check(unsafe {
+            flash_erase(
+                &mut self.pfr.flash_config,
+                address as u32,
+                len as u32,
+                ERASE_KEY,
+            )
+        })
+    }
+
+    /// Program `data` (whole pages) at `address`, which must be erased.
+    pub fn program(&mut self, address: usize, data: &[u8]) -> core::result::Result<(), RomError> {
+        let flash_program = Pfr::<Enabled>::bootloader_api_tree()
+            .flash_driver
+            .flash_program;
+        Self::check(unsafe {
+            flash_program(
+                &mut self.pfr.flash_config,
+                address as u32,
+                data.as_ptr(),
+                data.len() as u32,
+            )
+        })
+    }
+
+    /// Check that `len` bytes (whole pages) at `address` are erased.
+    pub fn verify_erase(
+        &mut self,
+        address: usize,
+        len: usize,
+    ) -> core::result::Result<(), RomError> {
+        let flash_verify_erase = Pfr::<Enabled>::bootloader_api_tree()
+            .flash_driver
+            .flash_verify_erase;
+        Self::check(unsafe {
+            flash_verify_erase(&mut self.pfr.flash_config, address as u32, len as u32)
+        })
+    }
+
+    /// Check that flash at `address` contains `data`.
+    pub fn verify_program(
+        &mut self,
+        address: usize,
+        data: &[u8],
+    ) -> core::result::Result<(), RomError> {
+        let flash_verify_program = Pfr::<Enabled>::bootloader_api_tree()
+            .flash_driver
+            .flash_verify_program;
+        let mut failed_address = 0u32;
+        let mut failed_data = 0u32;
+        let status = unsafe {
+            flash_verify_program(
+                &mut self.pfr.flash_config,
+                address as u32,
+                data.len() as u32,
+                data.as_ptr(),
+                &mut failed_address,
+                &mut failed_data,
+            )
+        };
+        match status {
+            0 => Ok(()),
+            STATUS_COMPARE_ERROR => Err(RomError::VerifyFailed {
+                address: failed_address,
+                data: failed_data,
+            }),
+            status => Err(RomError::Status(status)),
+        }
+    }
+
+    pub fn property(&mut self, property: Property) -> core::result::Result<u32, RomError> {
+        let flash_get_property = Pfr::<Enabled>::bootloader_api_tree()
+            .flash_driver
+            .flash_get_property;
+        let mut value = 0u32;
+        Self::check(unsafe {
+            flash_get_property(&mut self.pfr.flash_config, property as u32, &mut value)
+        })?;
+        Ok(value)
+    }
+
+    fn check(status: u32) -> core::result::Result<(), RomError> {
+        match status {
+            0 => Ok(()),
+            status => Err(RomError::Status(status)),
+        }
+    }
+
+    // reading erased flash over the bus faults, so pages are checked first
+    fn is_blank(&self, page_address: usize) -> bool {
+        let flash_verify_erase = Pfr::<Enabled>::bootloader_api_tree()
+            .flash_driver
+            .flash_verify_erase;
+        // the ROM wants a mutable config, reads only get a shared one
+        let mut config = self.pfr.flash_config.clone();
+        unsafe { flash_verify_erase(&mut config, page_address as u32, PAGE_SIZE as u32) == 0 }
+    }
+
+    // `bytes` within the page at `page_address`
+    fn read_page(&self, page_address: usize, address: usize, bytes: &mut [u8]) {
+        if self.is_blank(page_address) {
+            bytes.iter_mut().for_each(|byte| *byte = 0xff);
+            return;
+        }
+        let flash = address as *const u8;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile(flash.add(i)) };
+        }
+    }
+
+    fn record(&mut self, result: core::result::Result<(), RomError>) -> Result {
+        result.map_err(|error| {
+            self.last_error = Some(error);
+            error.into()
+        })
+    }
+}
+
+impl Read<U16> for RomFlash {
+    /// Corrected ECC errors are not reported, see the module documentation.
+    fn read_native(&self, address: usize, array: &mut GenericArray<u8, U16>) -> Result<EccStatus> {
+        if address % READ_SIZE != 0 {
+            return Err(Error::Illegal);
+        }
+        self.read_page(address & !(PAGE_SIZE - 1), address, array);
+        Ok(EccStatus::Clean)
+    }
+
+    /// Like `read_native`, checking each page for blankness only once.
+    ///
+    /// Nothing is cached beyond a single call, as pages may also be erased or
+    /// programmed without going through `RomFlash`.
+    fn read(&self, address: usize, buf: &mut [u8]) -> Result<EccStatus> {
+        if address % READ_SIZE != 0 || buf.len() % READ_SIZE != 0 {
+            return Err(Error::Illegal);
+        }
+        let mut offset = 0;
+        while offset < buf.len() {
+            let current = address + offset;
+            let page_address = current & !(PAGE_SIZE - 1);
+            let len = min(page_address + PAGE_SIZE - current, buf.len() - offset);
+            self.read_page(page_address, current, &mut buf[offset..][..len]);
+            offset += len;
+        }
+        Ok(EccStatus::Clean)
+    }
+}
+
+impl WriteErase<U512, U512> for RomFlash {
+    fn status(&self) -> Result {
+        match self.last_error {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result {
+        self.last_error = None;
+        let address = page * PAGE_SIZE;
+        let result = self
+            .erase(address, PAGE_SIZE)
+            .and_then(|_| self.verify_erase(address, PAGE_SIZE));
+        self.record(result)
+    }
+
+    fn write_native(&mut self, address: usize, array: &GenericArray<u8, U512>) -> Result {
+        self.last_error = None;
+        let result = self
+            .program(address, array)
+            .and_then(|_| self.verify_program(address, array));
+        self.record(result)
+    }
+}
@@ -0,0 +1,308 @@
+//! RAM-backed flash, for testing code generic over the flash traits.
+//!
+//! `SimFlash` follows the rules of the real flash controller:
+//! - reads must be physical word aligned (16 bytes), erase and write
+//!   page aligned (512 bytes),
+//! - erased memory reads as 0xff,
+//! - programming a page that was not erased since it was last programmed
+//!   does not fail, but leaves words whose ECC no longer matches; reading
+//!   such words fails with `EccError`.
+//!
+//! Correctable ECC errors can be injected with `inject_correctable`.
+//!
+//! For fault injection, `power_loss_after` cuts the power once a given number
+//! of physical words was programmed (an erase counts as one word). The
+//! operation in progress is left torn, and all later ones fail until
+//! `power_cycle` is called.
+
+use core::cell::Cell;
+
+use super::{GenericArray, PAGE_SIZE, PAGE_WORDS, READ_SIZE, U16, U512};
+use crate::traits::flash::{EccStatus, Error, Read, Result, WriteErase};
+
+pub struct SimFlash<const PAGES: usize> {
+    memory: [[u8; PAGE_SIZE]; PAGES],
+    // pages programmed since their last erase
+    programmed: [bool; PAGES],
+    // one bit per physical word whose ECC does not match
+    corrupted: [u32; PAGES],
+    // one bit per physical word with a correctable error
+    correctable: [u32; PAGES],
+    status: Cell<Result>,
+    // remaining words before power loss
+    budget: Option<usize>,
+    powered: bool,
+}
+
+impl<const PAGES: usize> Default for SimFlash<PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGES: usize> SimFlash<PAGES> {
+    /// Fully erased flash of `PAGES` pages, at addresses `0..PAGES * 512`.
+    pub fn new() -> Self {
+        SimFlash {
+            memory: [[0xff; PAGE_SIZE]; PAGES],
+            programmed: [false; PAGES],
+            corrupted: [0; PAGES],
+            correctable: [0; PAGES],
+            status: Cell::new(Ok(())),
+            budget: None,
+            powered: true,
+        }
+    }
+
+    /// Raw content of a page.
+    pub fn page(&self, page: usize) -> &[u8; PAGE_SIZE] {
+        &self.memory[page]
+    }
+
+    /// Make reads of the physical word containing `address` fail ECC checks.
+    pub fn corrupt(&mut self, address: usize) {
+        self.corrupted[address / PAGE_SIZE] |= 1 << ((address % PAGE_SIZE) / READ_SIZE);
+    }
+
+    /// Make reads of the physical word containing `address` report a corrected error.
+    pub fn inject_correctable(&mut self, address: usize) {
+        self.correctable[address / PAGE_SIZE] |= 1 << ((address % PAGE_SIZE) / READ_SIZE);
+    }
+
+    /// Lose power after `words` more physical words are programmed.
+    pub fn power_loss_after(&mut self, words: usize) {
+        self.budget = Some(words);
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Restore power (flash content is kept, of course).
+    pub fn power_cycle(&mut self) {
+        self.budget = None;
+        self.powered = true;
+        self.status.set(Ok(()));
+    }
+
+    // consume one word of the power budget, returns false on power loss
+    fn spend(&mut self) -> bool {
+        match self.budget {
+            Some(0) => {
+                self.powered = false;
+                false
+            }
+            Some(ref mut budget) => {
+                *budget -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn finish(&self, result: Result) -> Result {
+        self.status.set(result);
+        result
+    }
+}
+
+impl<const PAGES: usize> Read<U16> for SimFlash<PAGES> {
+    fn read_native(&self, address: usize, array: &mut GenericArray<u8, U16>) -> Result<EccStatus> {
+        if !self.powered {
+            return Err(Error::Failure);
+        }
+        if address % READ_SIZE != 0 || address >= PAGES * PAGE_SIZE {
+            self.status.set(Err(Error::Illegal));
+            return Err(Error::Illegal);
+        }
+
+        let (page, offset) = (address / PAGE_SIZE, address % PAGE_SIZE);
+        let word = 1 << (offset / READ_SIZE);
+        array.copy_from_slice(&self.memory[page][offset..][..READ_SIZE]);
+        if self.corrupted[page] & word != 0 {
+            self.status.set(Err(Error::EccError));
+            return Err(Error::EccError);
+        }
+        self.status.set(Ok(()));
+        match self.correctable[page] & word != 0 {
+            true => Ok(EccStatus::Corrected),
+            false => Ok(EccStatus::Clean),
+        }
+    }
+}
+
+impl<const PAGES: usize> WriteErase<U512, U512> for SimFlash<PAGES> {
+    fn status(&self) -> Result {
+        self.status.get()
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result {
+        if !self.powered {
+            return self.finish(Err(Error::Failure));
+        }
+        if page >= PAGES {
+            return self.finish(Err(Error::Illegal));
+        }
+        if !self.spend() {
+            // torn erase: content is unreliable
+            self.corrupted[page] = !0;
+            return self.finish(Err(Error::Failure));
+        }
+
+        self.memory[page] = [0xff; PAGE_SIZE];
+        self.programmed[page] = false;
+        self.corrupted[page] = 0;
+        self.correctable[page] = 0;
+        self.finish(Ok(()))
+    }
+
+    fn write_native(&mut self, address: usize, array: &GenericArray<u8, U512>) -> Result {
+        if !self.powered {
+            return self.finish(Err(Error::Failure));
+        }
+        if address % PAGE_SIZE != 0 || address >= PAGES * PAGE_SIZE {
+            return self.finish(Err(Error::Illegal));
+        }
+
+        let page = address / PAGE_SIZE;
+        let reprogrammed = self.programmed[page];
+        self.programmed[page] = true;
+        for word in 0..PAGE_WORDS {
+            if !self.spend() {
+                // torn program: the word in progress is unreliable
+                self.corrupted[page] |= 1 << word;
+                return self.finish(Err(Error::Failure));
+            }
+            let range = word * READ_SIZE..(word + 1) * READ_SIZE;
+            // programming can only clear bits
+            for (old, new) in self.memory[page][range.clone()]
+                .iter_mut()
+                .zip(&array[range])
+            {
+                *old &= new;
+            }
+            if reprogrammed {
+                self.corrupted[page] |= 1 << word;
+            }
+        }
+        self.finish(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Flash = SimFlash<2>;
+
+    fn page(byte: u8) -> GenericArray<u8, U512> {
+        GenericArray::from([byte; PAGE_SIZE])
+    }
+
+    fn word(flash: &Flash, address: usize) -> Result<(EccStatus, [u8; READ_SIZE])> {
+        let mut word = GenericArray::<u8, U16>::default();
+        let status = flash.read_native(address, &mut word)?;
+        Ok((status, word.into()))
+    }
+
+    #[test]
+    fn alignment() {
+        let mut flash = Flash::new();
+        assert_eq!(word(&flash, 8), Err(Error::Illegal));
+        assert_eq!(flash.status(), Err(Error::Illegal));
+        assert_eq!(word(&flash, 2 * PAGE_SIZE), Err(Error::Illegal));
+        assert_eq!(word(&flash, 16), Ok((EccStatus::Clean, [0xff; 16])));
+        assert_eq!(flash.status(), Ok(()));
+
+        assert_eq!(flash.write_native(16, &page(0)), Err(Error::Illegal));
+        assert_eq!(
+            flash.write_native(2 * PAGE_SIZE, &page(0)),
+            Err(Error::Illegal)
+        );
+        assert_eq!(flash.erase_page(2), Err(Error::Illegal));
+        assert_eq!(flash.status(), Err(Error::Illegal));
+        assert_eq!(flash.write_native(PAGE_SIZE, &page(0x5a)), Ok(()));
+        assert_eq!(flash.page(1), &[0x5a; PAGE_SIZE]);
+        assert_eq!(flash.page(0), &[0xff; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn reprogram_without_erase() {
+        let mut flash = Flash::new();
+        flash.write_native(0, &page(0x0f)).unwrap();
+        assert_eq!(word(&flash, 0), Ok((EccStatus::Clean, [0x0f; 16])));
+
+        // succeeds, but bits are only cleared and the ECC no longer matches
+        flash.write_native(0, &page(0xf3)).unwrap();
+        assert_eq!(flash.page(0), &[0x03; PAGE_SIZE]);
+        assert_eq!(word(&flash, 0), Err(Error::EccError));
+        assert_eq!(word(&flash, PAGE_SIZE - 16), Err(Error::EccError));
+        assert_eq!(flash.status(), Err(Error::EccError));
+
+        flash.erase_page(0).unwrap();
+        assert_eq!(word(&flash, 0), Ok((EccStatus::Clean, [0xff; 16])));
+    }
+
+    #[test]
+    fn correctable_errors() {
+        let mut flash = Flash::new();
+        flash.inject_correctable(PAGE_SIZE + 40);
+        assert_eq!(
+            word(&flash, PAGE_SIZE + 32),
+            Ok((EccStatus::Corrected, [0xff; 16]))
+        );
+        assert_eq!(
+            word(&flash, PAGE_SIZE + 48),
+            Ok((EccStatus::Clean, [0xff; 16]))
+        );
+
+        let mut buf = [0u8; PAGE_SIZE];
+        assert_eq!(flash.read(0, &mut buf), Ok(EccStatus::Clean));
+        assert_eq!(flash.read(PAGE_SIZE, &mut buf), Ok(EccStatus::Corrected));
+
+        flash.corrupt(0);
+        assert_eq!(flash.read(0, &mut buf), Err(Error::EccError));
+    }
+
+    #[test]
+    fn torn_program() {
+        let mut flash = Flash::new();
+        flash.power_loss_after(5);
+        assert_eq!(flash.write_native(0, &page(0)), Err(Error::Failure));
+        assert!(!flash.is_powered());
+
+        // everything fails until power is back
+        assert_eq!(word(&flash, 0), Err(Error::Failure));
+        assert_eq!(flash.erase_page(1), Err(Error::Failure));
+        assert_eq!(flash.write_native(PAGE_SIZE, &page(0)), Err(Error::Failure));
+        flash.power_cycle();
+        assert!(flash.is_powered());
+        assert_eq!(flash.page(1), &[0xff; PAGE_SIZE]);
+
+        for i in 0..5 {
+            assert_eq!(word(&flash, 16 * i), Ok((EccStatus::Clean, [0; 16])));
+        }
+        assert_eq!(word(&flash, 16 * 5), Err(Error::EccError));
+        for i in 6..PAGE_WORDS {
+            assert_eq!(word(&flash, 16 * i), Ok((EccStatus::Clean, [0xff; 16])));
+        }
+    }
+
+    #[test]
+    fn torn_erase() {
+        let mut flash = Flash::new();
+        flash.write_native(0, &page(0)).unwrap();
+        flash.power_loss_after(0);
+        assert_eq!(flash.erase_page(0), Err(Error::Failure));
+        flash.power_cycle();
+        for i in 0..PAGE_WORDS {
+            assert_eq!(word(&flash, 16 * i), Err(Error::EccError));
+        }
+
+        // the budget counts words across operations
+        flash.power_loss_after(PAGE_WORDS + 1);
+        flash.erase_page(0).unwrap();
+        flash.write_native(0, &page(0)).unwrap();
+        assert_eq!(flash.erase_page(1), Err(Error::Failure));
+    }
+}
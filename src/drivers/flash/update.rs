@@ -0,0 +1,597 @@
+//! A/B firmware updates.
+//!
+//! Two equally sized flash regions are used as slots: one holds the active
+//! (confirmed) image, the other receives updates. Which slot to boot is
+//! recorded in a journal of two pages, written alternately, so that a reset
+//! at any point leaves at least one intact record behind.
+//!
+//! The flow is:
+//! - `Updater::begin` returns an `ImageWriter` that streams a new image
+//!   into the inactive slot,
+//! - `ImageWriter::finish` verifies it against its SHA-256 digest (e.g.
+//!   with the `Sha256` driver) and marks it pending,
+//! - on the next start, `Updater::boot` selects the pending image once;
+//!   if it is not confirmed with `Updater::confirm` before the following
+//!   start, it is rolled back.
+
+use core::convert::TryInto;
+
+use digest::{consts::U32, FixedOutput, Update};
+
+use super::{crc32, GenericArray, PAGE_SIZE, READ_SIZE, U16, U512};
+use crate::traits::flash::{self, Read, WriteErase};
+
+const MAGIC: u32 = 0x5550_4454;
+// magic, seq, active, state, tried, padding, crc
+const RECORD_SIZE: usize = READ_SIZE;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    Flash(flash::Error),
+    /// Slots or journal are not page aligned, or overlap
+    Misconfigured,
+    /// Image does not fit in a slot
+    ImageTooLarge,
+    /// Image digest does not match the expected one
+    VerificationFailed,
+    /// Operation not possible in the current state
+    InvalidState,
+}
+
+impl From<flash::Error> for Error {
+    fn from(error: flash::Error) -> Self {
+        Error::Flash(error)
+    }
+}
+
+pub type Result<T = ()> = core::result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SlotId {
+    A = 0,
+    B = 1,
+}
+
+impl SlotId {
+    pub fn other(self) -> Self {
+        match self {
+            SlotId::A => SlotId::B,
+            SlotId::B => SlotId::A,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    /// The active slot is confirmed, no update in progress
+    Confirmed,
+    /// The inactive slot holds a verified image, not yet confirmed
+    Pending,
+    /// A pending image was started, but not confirmed, and rolled back
+    Rollback,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Record {
+    seq: u32,
+    active: SlotId,
+    state: State,
+    // the pending image was already selected by `boot`
+    tried: bool,
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Record {
+            seq: 0,
+            active: SlotId::A,
+            state: State::Confirmed,
+            tried: false,
+        }
+    }
+}
+
+impl Record {
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8] = self.active as u8;
+        bytes[9] = self.state as u8;
+        bytes[10] = self.tried as u8;
+        let crc = crc32(&bytes[..12]);
+        bytes[12..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(12) != crc32(&bytes[..12]) {
+            return None;
+        }
+        Some(Record {
+            seq: word(4),
+            active: match bytes[8] {
+                0 => SlotId::A,
+                1 => SlotId::B,
+                _ => return None,
+            },
+            state: match bytes[9] {
+                0 => State::Confirmed,
+                1 => State::Pending,
+                2 => State::Rollback,
+                _ => return None,
+            },
+            tried: bytes[10] != 0,
+        })
+    }
+}
+
+/// Configuration of an `Updater`.
+///
+/// All addresses are byte offsets into flash, and must be page aligned.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UpdateBuilder {
+    slots: [usize; 2],
+    slot_size: usize,
+    journal: usize,
+}
+
+impl UpdateBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn slot_a(mut self, address: usize) -> Self {
+        self.slots[SlotId::A as usize] = address;
+        self
+    }
+
+    pub fn slot_b(mut self, address: usize) -> Self {
+        self.slots[SlotId::B as usize] = address;
+        self
+    }
+
+    pub fn slot_size(mut self, size: usize) -> Self {
+        self.slot_size = size;
+        self
+    }
+
+    /// The journal occupies the two pages starting at `address`.
+    pub fn journal(mut self, address: usize) -> Self {
+        self.journal = address;
+        self
+    }
+
+    pub fn build<F>(self, flash: F) -> Result<Updater<F>>
+    where
+        F: Read<U16> + WriteErase<U512, U512>,
+    {
+        let regions = [
+            (self.slots[0], self.slot_size),
+            (self.slots[1], self.slot_size),
+            (self.journal, 2 * PAGE_SIZE),
+        ];
+        for (i, &(start, len)) in regions.iter().enumerate() {
+            if start % PAGE_SIZE != 0 || len % PAGE_SIZE != 0 || len == 0 {
+                return Err(Error::Misconfigured);
+            }
+            for &(other_start, other_len) in &regions[i + 1..] {
+                if start < other_start + other_len && other_start < start + len {
+                    return Err(Error::Misconfigured);
+                }
+            }
+        }
+
+        let mut updater = Updater {
+            flash,
+            config: self,
+            record: Record::default(),
+            // the page *not* holding the current record
+            next_journal_page: 0,
+        };
+        updater.load()?;
+        Ok(updater)
+    }
+}
+
+pub struct Updater<F> {
+    flash: F,
+    config: UpdateBuilder,
+    record: Record,
+    next_journal_page: usize,
+}
+
+impl<F> Updater<F>
+where
+    F: Read<U16> + WriteErase<U512, U512>,
+{
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    pub fn state(&self) -> State {
+        self.record.state
+    }
+
+    /// The slot holding the confirmed image.
+    pub fn active(&self) -> SlotId {
+        self.record.active
+    }
+
+    /// Start address of a slot.
+    pub fn slot_address(&self, slot: SlotId) -> usize {
+        self.config.slots[slot as usize]
+    }
+
+    /// Select the slot to boot, to be called once per start.
+    ///
+    /// A pending image is selected exactly once; if it was already selected
+    /// and not confirmed since, it is rolled back.
+    pub fn boot(&mut self) -> Result<SlotId> {
+        let mut record = self.record;
+        match (record.state, record.tried) {
+            (State::Pending, false) => {
+                record.tried = true;
+                self.commit(record)?;
+                Ok(record.active.other())
+            }
+            (State::Pending, true) => {
+                record.state = State::Rollback;
+                record.tried = false;
+                self.commit(record)?;
+                Ok(record.active)
+            }
+            _ => Ok(record.active),
+        }
+    }
+
+    /// Confirm the pending image, once it was booted and found working.
+    pub fn confirm(&mut self) -> Result {
+        let mut record = self.record;
+        if record.state != State::Pending || !record.tried {
+            return Err(Error::InvalidState);
+        }
+        record.active = record.active.other();
+        record.state = State::Confirmed;
+        record.tried = false;
+        self.commit(record)
+    }
+
+    /// Start streaming a new image into the inactive slot.
+    ///
+    /// A pending image that was not yet booted is discarded. While a pending
+    /// image is running unconfirmed, its slot cannot be overwritten.
+    pub fn begin(&mut self) -> Result<ImageWriter<'_, F>> {
+        let mut record = self.record;
+        match (record.state, record.tried) {
+            (State::Pending, true) => return Err(Error::InvalidState),
+            (State::Pending, false) | (State::Rollback, _) => {
+                record.state = State::Confirmed;
+                record.tried = false;
+                self.commit(record)?;
+            }
+            _ => {}
+        }
+
+        Ok(ImageWriter {
+            base: self.slot_address(record.active.other()),
+            updater: self,
+            page: GenericArray::default(),
+            len: 0,
+        })
+    }
+
+    fn load(&mut self) -> Result {
+        let mut records: [Option<Record>; 2] = [None, None];
+        for (i, record) in records.iter_mut().enumerate() {
+            let address = self.config.journal + i * PAGE_SIZE;
+            // a record is one physical word
+            let mut bytes = [0u8; RECORD_SIZE];
+            *record = match self.flash.read(address, &mut bytes) {
+                Ok(_) => Record::from_bytes(&bytes),
+                // as good as a torn record
+                Err(flash::Error::EccError) => None,
+                Err(error) => return Err(error.into()),
+            };
+        }
+
+        let current = match records {
+            [Some(a), Some(b)] => {
+                if b.seq.wrapping_sub(a.seq) as i32 > 0 {
+                    1
+                } else {
+                    0
+                }
+            }
+            [Some(_), None] => 0,
+            [None, Some(_)] => 1,
+            [None, None] => {
+                // fresh device: slot A is active, first record goes to page 0
+                self.record = Record::default();
+                self.next_journal_page = 0;
+                return Ok(());
+            }
+        };
+        self.record = records[current].unwrap();
+        self.next_journal_page = 1 - current;
+        Ok(())
+    }
+
+    // writes the record to the journal page not holding the current one,
+    // so the current one survives if this is interrupted
+    fn commit(&mut self, mut record: Record) -> Result {
+        record.seq = self.record.seq.wrapping_add(1);
+        let mut page = GenericArray::<u8, U512>::default();
+        page[..RECORD_SIZE].copy_from_slice(&record.to_bytes());
+
+        let address = self.config.journal + self.next_journal_page * PAGE_SIZE;
+        self.flash.erase_page(address / PAGE_SIZE)?;
+        self.flash.write_native(address, &page)?;
+
+        self.record = record;
+        self.next_journal_page = 1 - self.next_journal_page;
+        Ok(())
+    }
+}
+
+/// Streams an image into the inactive slot, see `Updater::begin`.
+pub struct ImageWriter<'a, F>
+where
+    F: Read<U16> + WriteErase<U512, U512>,
+{
+    updater: &'a mut Updater<F>,
+    base: usize,
+    page: GenericArray<u8, U512>,
+    len: usize,
+}
+
+impl<F> ImageWriter<'_, F>
+where
+    F: Read<U16> + WriteErase<U512, U512>,
+{
+    pub fn write(&mut self, mut data: &[u8]) -> Result {
+        if self.len + data.len() > self.updater.config.slot_size {
+            return Err(Error::ImageTooLarge);
+        }
+        while !data.is_empty() {
+            let offset = self.len % PAGE_SIZE;
+            let n = core::cmp::min(PAGE_SIZE - offset, data.len());
+            self.page[offset..][..n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+
+            if self.len % PAGE_SIZE == 0 {
+                self.flush_page()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the remainder of the image, check it against the expected
+    /// SHA-256 `digest` using `sha256`, and mark it pending.
+    pub fn finish<D>(mut self, mut sha256: D, digest: &[u8; 32]) -> Result
+    where
+        D: Update + FixedOutput<OutputSize = U32>,
+    {
+        if self.len % PAGE_SIZE != 0 {
+            let offset = self.len % PAGE_SIZE;
+            for byte in self.page[offset..].iter_mut() {
+                *byte = 0xff;
+            }
+            self.flush_page()?;
+        }
+
+        let mut page = GenericArray::<u8, U512>::default();
+        for offset in (0..self.len).step_by(PAGE_SIZE) {
+            self.updater.flash.read(self.base + offset, &mut page)?;
+            let n = core::cmp::min(PAGE_SIZE, self.len - offset);
+            sha256.update(&page[..n]);
+        }
+        if sha256.finalize_fixed().as_slice() != digest {
+            return Err(Error::VerificationFailed);
+        }
+
+        let mut record = self.updater.record;
+        record.state = State::Pending;
+        record.tried = false;
+        self.updater.commit(record)
+    }
+
+    fn flush_page(&mut self) -> Result {
+        // `len` is past the data in `page`
+        let address = self.base + ((self.len - 1) & !(PAGE_SIZE - 1));
+        self.updater.flash.erase_page(address / PAGE_SIZE)?;
+        self.updater.flash.write_native(address, &self.page)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::flash::SimFlash;
+    use sha2::{Digest, Sha256};
+
+    type Flash = SimFlash<10>;
+
+    const SLOT_SIZE: usize = 4 * PAGE_SIZE;
+
+    fn mount(flash: Flash) -> Updater<Flash> {
+        UpdateBuilder::new()
+            .slot_a(0)
+            .slot_b(SLOT_SIZE)
+            .slot_size(SLOT_SIZE)
+            .journal(2 * SLOT_SIZE)
+            .build(flash)
+            .unwrap()
+    }
+
+    fn image() -> [u8; 1000] {
+        let mut image = [0u8; 1000];
+        for (i, byte) in image.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        image
+    }
+
+    fn install(updater: &mut Updater<Flash>) -> Result {
+        let image = image();
+        let digest: [u8; 32] = Sha256::digest(image).into();
+        let mut writer = updater.begin()?;
+        // in uneven chunks
+        for chunk in image.chunks(300) {
+            writer.write(chunk)?;
+        }
+        writer.finish(Sha256::new(), &digest)
+    }
+
+    // simulates a reset
+    fn restart(updater: Updater<Flash>) -> Updater<Flash> {
+        mount(updater.release())
+    }
+
+    #[test]
+    fn update_and_confirm() {
+        let mut updater = mount(Flash::new());
+        assert_eq!(updater.state(), State::Confirmed);
+        assert_eq!(updater.boot(), Ok(SlotId::A));
+
+        install(&mut updater).unwrap();
+        assert_eq!(updater.state(), State::Pending);
+        let mut read_back = [0u8; 1008];
+        updater
+            .flash
+            .read(updater.slot_address(SlotId::B), &mut read_back)
+            .unwrap();
+        assert_eq!(read_back[..1000], image());
+
+        let mut updater = restart(updater);
+        assert_eq!(updater.state(), State::Pending);
+        assert_eq!(updater.boot(), Ok(SlotId::B));
+        updater.confirm().unwrap();
+        assert_eq!(updater.active(), SlotId::B);
+
+        let mut updater = restart(updater);
+        assert_eq!(updater.state(), State::Confirmed);
+        assert_eq!(updater.boot(), Ok(SlotId::B));
+        // the next update goes to slot A
+        assert_eq!(updater.begin().unwrap().base, 0);
+    }
+
+    #[test]
+    fn rollback_unconfirmed() {
+        let mut updater = mount(Flash::new());
+        install(&mut updater).unwrap();
+        // confirming an image that was never booted is refused
+        assert_eq!(updater.confirm(), Err(Error::InvalidState));
+
+        let mut updater = restart(updater);
+        assert_eq!(updater.boot(), Ok(SlotId::B));
+        // running unconfirmed, its slot cannot be overwritten
+        assert!(matches!(updater.begin(), Err(Error::InvalidState)));
+
+        // reset without confirming
+        let mut updater = restart(updater);
+        assert_eq!(updater.boot(), Ok(SlotId::A));
+        assert_eq!(updater.state(), State::Rollback);
+        assert_eq!(updater.confirm(), Err(Error::InvalidState));
+
+        let mut updater = restart(updater);
+        assert_eq!(updater.boot(), Ok(SlotId::A));
+    }
+
+    #[test]
+    fn reject_wrong_digest() {
+        let mut updater = mount(Flash::new());
+        let mut writer = updater.begin().unwrap();
+        writer.write(&image()).unwrap();
+        assert_eq!(
+            writer.finish(Sha256::new(), &[0; 32]),
+            Err(Error::VerificationFailed)
+        );
+        assert_eq!(updater.state(), State::Confirmed);
+
+        let mut writer = updater.begin().unwrap();
+        assert_eq!(writer.write(&[0; SLOT_SIZE + 1]), Err(Error::ImageTooLarge));
+    }
+
+    #[test]
+    fn power_loss_in_journal_write() {
+        // an erase and 32 words per journal write
+        for words in 0..=PAGE_SIZE / READ_SIZE + 1 {
+            let mut updater = mount(Flash::new());
+            install(&mut updater).unwrap();
+            let mut updater = restart(updater);
+
+            updater.flash.power_loss_after(words);
+            let result = updater.boot();
+            let mut flash = updater.release();
+            let lost = !flash.is_powered();
+            flash.power_cycle();
+
+            let mut updater = mount(flash);
+            if lost {
+                assert!(result.is_err());
+                // either the previous record survives, or the new one is complete
+                assert_eq!(updater.state(), State::Pending);
+                assert!(words > 0 || !updater.record.tried);
+                let expected = match updater.record.tried {
+                    false => SlotId::B,
+                    true => SlotId::A,
+                };
+                assert_eq!(updater.boot(), Ok(expected));
+            } else {
+                assert_eq!(result, Ok(SlotId::B));
+                // tried, so rolled back
+                assert_eq!(updater.boot(), Ok(SlotId::A));
+            }
+        }
+    }
+
+    #[test]
+    fn power_loss_at_every_word() {
+        let old = [0xa5u8; SLOT_SIZE];
+        let new = image();
+        // update, boot and confirm
+        fn run(updater: &mut Updater<Flash>) -> Result {
+            install(updater)?;
+            updater.boot()?;
+            updater.confirm()
+        }
+
+        for words in 0.. {
+            let mut flash = Flash::new();
+            flash.write(0, &old).unwrap();
+            flash.power_loss_after(words);
+            let mut updater = mount(flash);
+            let result = run(&mut updater);
+            let mut flash = updater.release();
+            if flash.is_powered() {
+                assert_eq!(result, Ok(()));
+                // two image pages and three journal writes
+                assert!(words > 5 * PAGE_SIZE / READ_SIZE);
+                break;
+            }
+            flash.power_cycle();
+
+            // whichever slot is booted holds a complete image
+            let mut updater = mount(flash);
+            let slot = updater.boot().unwrap();
+            let address = updater.slot_address(slot);
+            let mut read_back = [0u8; 1008];
+            updater.flash.read(address, &mut read_back).unwrap();
+            match slot {
+                SlotId::A => assert_eq!(read_back, old[..1008]),
+                SlotId::B => assert_eq!(read_back[..1000], new),
+            }
+
+            // and updates work again
+            if updater.state() == State::Pending {
+                updater.confirm().unwrap();
+            }
+            let mut updater = restart(updater);
+            install(&mut updater).unwrap();
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Hardware key store: PUF key codes persisted in the key store area of CMPA.
+//!
+//! Provisioning enrolls the PUF once, generates a key code per `KeyType` and
+//! writes them through the boot ROM.  At every boot, the PUF is started from
+//! the stored activation code and the keys are derived again from their codes.
+//!
+//! Keys consumed by hardware (SBKEK by AES, PRINCE regions by PRINCE) never
+//! leave the PUF, USER and UDS keys can be read by software.
+//!
+//! Provisioning leaves the PUF enrolled, not started: keys are available after
+//! the next reset, as in `examples/keystore.rs`:
+//!
+//! ```ignore
+//! let mut key_store = PufKeyStore::new(puf, pfr);
+//! if !key_store.is_provisioned() {
+//!     key_store.provision()?;
+//!     // reset
+//! } else {
+//!     let mut key_store = key_store.start()?;
+//!     key_store.load_key(KeyType::Sbkek)?;
+//!     let user_key = key_store.key(KeyType::User)?;
+//! }
+//! ```
+
+use crate::{
+    peripherals::{
+        pfr::{KeyStore, KeyType, Pfr},
+        puf::{self, ActivationCode, Enrolled, KeyCode, Puf, Started},
+    },
+    raw::puf::keyenable::KEY_A,
+    traits::digest::generic_array::{
+        typenum::{U16, U32},
+        GenericArray,
+    },
+    typestates::init_state::Enabled,
+};
+
+const KEY_TYPES: [KeyType; 6] = [
+    KeyType::Sbkek,
+    KeyType::User,
+    KeyType::Uds,
+    KeyType::PrinceRegion0,
+    KeyType::PrinceRegion1,
+    KeyType::PrinceRegion2,
+];
+
+/// Key store error
+#[derive(Debug)]
+pub enum Error {
+    /// PUF command failed
+    Puf(puf::Error),
+    /// Status code returned by the boot ROM
+    Rom(u32),
+    /// Key store holds no activation code
+    NotProvisioned,
+    /// Key is sent to hardware and cannot be read, or vice versa
+    WrongKeyType,
+}
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl From<puf::Error> for Error {
+    fn from(error: puf::Error) -> Self {
+        Error::Puf(error)
+    }
+}
+
+// IP the key is sent to, `NONE` for keys read by software
+fn destination(key_type: KeyType) -> KEY_A {
+    match key_type {
+        KeyType::Sbkek => KEY_A::AES,
+        KeyType::User | KeyType::Uds => KEY_A::NONE,
+        KeyType::PrinceRegion0 => KEY_A::PRINCE0,
+        KeyType::PrinceRegion1 => KEY_A::PRINCE1,
+        KeyType::PrinceRegion2 => KEY_A::PRINCE2,
+    }
+}
+
+/// PUF with its key store in CMPA, in state `State` of the PUF.
+pub struct PufKeyStore<State = ()> {
+    puf: Puf<Enabled<State>>,
+    pfr: Pfr<Enabled>,
+}
+
+impl<State> PufKeyStore<State> {
+    pub fn release(self) -> (Puf<Enabled<State>>, Pfr<Enabled>) {
+        (self.puf, self.pfr)
+    }
+}
+
+impl PufKeyStore {
+    pub fn new(puf: Puf<Enabled>, pfr: Pfr<Enabled>) -> Self {
+        PufKeyStore { puf, pfr }
+    }
+
+    /// Whether the key store holds an activation code.
+    pub fn is_provisioned(&mut self) -> bool {
+        self.activation_code().is_ok()
+    }
+
+    fn activation_code(&mut self) -> Result<ActivationCode> {
+        let bytes = self.pfr.read_activation_code().map_err(Error::Rom)?;
+        ActivationCode::from_bytes(&bytes).map_err(|_| Error::NotProvisioned)
+    }
+
+    /// Enroll the PUF, generate key codes for all key types and write them to the key store.
+    ///
+    /// This is possible once per device, before CMPA is sealed.  Keys can
+    /// be derived after a reset, with `start`.
+    pub fn provision(self) -> Result<PufKeyStore<Enrolled>> {
+        let PufKeyStore { puf, mut pfr } = self;
+        let (puf, activation_code) = puf.enroll()?;
+
+        let mut key_store = KeyStore::new(&activation_code);
+        for key_type in KEY_TYPES.iter().copied() {
+            let key_index = match destination(key_type) {
+                KEY_A::NONE => 1,
+                _ => 0,
+            };
+            match key_type {
+                KeyType::PrinceRegion0 | KeyType::PrinceRegion1 | KeyType::PrinceRegion2 => {
+                    let key_code: KeyCode<U16> = puf.generate_key(key_index)?;
+                    key_store.set_key_code(key_type, &key_code);
+                }
+                _ => {
+                    let key_code: KeyCode<U32> = puf.generate_key(key_index)?;
+                    key_store.set_key_code(key_type, &key_code);
+                }
+            }
+        }
+
+        pfr.write_key_store(&key_store).map_err(Error::Rom)?;
+        Ok(PufKeyStore { puf, pfr })
+    }
+
+    /// Start the PUF with the stored activation code.
+    ///
+    /// If the ROM already started the PUF at boot, it is used as is.
+    pub fn start(mut self) -> Result<PufKeyStore<Started>> {
+        let activation_code = self.activation_code()?;
+        let puf = self.puf.try_start(&activation_code)?;
+        Ok(PufKeyStore { puf, pfr: self.pfr })
+    }
+}
+
+impl PufKeyStore<Started> {
+    /// Derive SBKEK or a PRINCE region key, sending it to its IP.
+    pub fn load_key(&mut self, key_type: KeyType) -> Result<()> {
+        let destination = destination(key_type);
+        if destination == KEY_A::NONE {
+            return Err(Error::WrongKeyType);
+        }
+        let bytes = self.pfr.read_key_code(key_type).map_err(Error::Rom)?;
+        let mut key = [0u8; 0];
+        match key_type {
+            KeyType::Sbkek => {
+                let key_code = KeyCode::<U32>::from_bytes(&bytes)?;
+                self.puf.get_key(destination, &key_code, &mut key)?;
+            }
+            _ => {
+                let key_code = KeyCode::<U16>::from_bytes(&bytes)?;
+                self.puf.get_key(destination, &key_code, &mut key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Derive the USER or UDS key.
+    pub fn key(&mut self, key_type: KeyType) -> Result<GenericArray<u8, U32>> {
+        if destination(key_type) != KEY_A::NONE {
+            return Err(Error::WrongKeyType);
+        }
+        let bytes = self.pfr.read_key_code(key_type).map_err(Error::Rom)?;
+        let key_code = KeyCode::<U32>::from_bytes(&bytes)?;
+        Ok(self.puf.unwrap(&key_code)?)
+    }
+}
@@ -1,11 +1,14 @@
-use core::marker::PhantomData;
+use core::{cmp::min, marker::PhantomData};
 
 use crate::traits::aligned::{Aligned, A4};
 use block_buffer::{BlockBuffer, Eager};
-use digest::{FixedOutput, Output, OutputSizeUser};
+use digest::{
+    core_api::BlockSizeUser, FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser,
+};
 
 use crate::{
     peripherals::hashcrypt::Hashcrypt,
+    raw,
     traits::{
         digest::generic_array::{
             typenum::{U20, U32, U64},
@@ -33,6 +36,47 @@ mod sealed {
 
 use sealed::OutputSize;
 
+pub mod hmac;
+pub use hmac::{Hmac, HmacSha256};
+
+pub mod sha512;
+pub use sha512::{Sha384, Sha512, SoftSha};
+
+// CTRL.RELOAD, and the RELOAD registers (not in the PAC), of revision 1B
+const CTRL_RELOAD: u32 = 1 << 5;
+const RELOAD_OFFSET: usize = 0xa0;
+
+const BLOCK_LEN: usize = 64;
+// limit of MEMCTRL.COUNT
+const MAX_BLOCKS: usize = 0x7ff;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Hash states can only be reloaded on chip revision 1B
+    ReloadUnsupported,
+    /// AHB bus error while the peripheral read memory, e.g. erased flash;
+    /// the hash computation is invalid and must be reset
+    Bus,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Intermediate state of a hash computation, see `Sha::save`.
+#[derive(Clone)]
+pub struct ShaState<Size: OutputSize> {
+    digest: [u32; 8],
+    buffer: BlockBuffer<BlockSize, Eager>,
+    len: u64,
+    size: PhantomData<Size>,
+}
+
+impl<Size: OutputSize> ShaState<Size> {
+    // whether any block went to the peripheral
+    fn started(&self) -> bool {
+        self.len >> 3 > self.buffer.get_pos() as u64
+    }
+}
+
 pub struct Sha<'a, Size: OutputSize> {
     buffer: Aligned<A4, BlockBuffer<BlockSize, Eager>>,
     inner: &'a mut Hashcrypt<Enabled>,
@@ -59,6 +103,80 @@ impl<'a, Size: OutputSize> Sha<'a, Size> {
         self.inner
     }
 
+    /// Export the intermediate state, to continue later with `Sha::restore`.
+    ///
+    /// Combined with `into_inner`, this allows time-sharing the peripheral
+    /// between several hash computations.
+    pub fn save(&self) -> ShaState<Size> {
+        let mut state = ShaState {
+            digest: [0; 8],
+            buffer: (*self.buffer).clone(),
+            len: self.len,
+            size: PhantomData,
+        };
+        if state.started() {
+            while self.inner.raw.status.read().digest().is_not_ready() {
+                continue;
+            }
+            for (i, word) in state.digest[..Size::to_usize() / 4].iter_mut().enumerate() {
+                *word = self.inner.raw.digest0[i].read().bits();
+            }
+        }
+        state
+    }
+
+    /// Hash `data`, letting the peripheral read whole blocks directly from
+    /// memory, up to 2047 blocks per transfer.
+    ///
+    /// Anything the peripheral can read works, including (PRINCE-decrypted)
+    /// flash. Data that is not word-aligned is hashed as with `update`.
+    pub fn update_region(&mut self, data: &[u8]) -> Result<()> {
+        unsafe { self.update_raw(data.as_ptr() as usize, data.len()) }
+    }
+
+    /// Hash the `len` bytes of memory at `address`, see `update_region`.
+    ///
+    /// # Safety
+    /// The memory region must be valid for reads.
+    pub unsafe fn digest_region(
+        hashcrypt: &'a mut Hashcrypt<Enabled>,
+        address: usize,
+        len: usize,
+    ) -> Result<Output<Self>> {
+        let mut sha = Self::new(hashcrypt);
+        sha.update_raw(address, len)?;
+        Ok(sha.finalize_fixed())
+    }
+
+    /// Continue a hash computation from a state exported by `Sha::save`.
+    ///
+    /// The peripheral can only reload a hash state on chip revision 1B,
+    /// on other revisions only states before the first complete block
+    /// can be restored.
+    pub fn restore(hashcrypt: &'a mut Hashcrypt<Enabled>, state: &ShaState<Size>) -> Result<Self> {
+        if state.started() && crate::chip_revision() != "1B" {
+            return Err(Error::ReloadUnsupported);
+        }
+
+        let mut sha = Self::new(hashcrypt);
+        sha.buffer = Aligned(state.buffer.clone());
+        sha.len = state.len;
+        if state.started() {
+            // cf `hashcrypt_restore_running_hash` in `fsl_hashcrypt.c`
+            let reload = (raw::HASHCRYPT::ptr() as usize + RELOAD_OFFSET) as *mut u32;
+            sha.inner
+                .ctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() | CTRL_RELOAD) });
+            for (i, word) in state.digest[..Size::to_usize() / 4].iter().enumerate() {
+                unsafe { core::ptr::write_volatile(reload.add(i), *word) };
+            }
+            sha.inner
+                .ctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & !CTRL_RELOAD) });
+        }
+        Ok(sha)
+    }
+
     pub fn reset(&mut self) {
         self.buffer = Aligned(Default::default());
         self.len = 0;
@@ -89,18 +207,28 @@ impl<'a, Size: OutputSize> From<&'a mut Hashcrypt<Enabled>> for Sha<'a, Size> {
 
 // the `digest` traits
 
+impl<Size: OutputSize> HashMarker for Sha<'_, Size> {}
+
 impl<Size: OutputSize> OutputSizeUser for Sha<'_, Size> {
     type OutputSize = Size;
 }
 
+impl<Size: OutputSize> BlockSizeUser for Sha<'_, Size> {
+    type BlockSize = U64;
+}
+
 impl<Size: OutputSize> FixedOutput for Sha<'_, Size> {
     fn finalize_into(mut self, out: &mut Output<Self>) {
         self.finish();
-        // cf `hashcrypt_get_data` ~line 315 of `fsl_hashcrypt.c`
-        for i in 0..Size::to_usize() / 4 {
-            out.as_mut_slice()[4 * i..4 * i + 4]
-                .copy_from_slice(&self.inner.raw.digest0[i].read().bits().to_be_bytes());
-        }
+        self.read_digest(out);
+    }
+}
+
+impl<Size: OutputSize> FixedOutputReset for Sha<'_, Size> {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        self.finish();
+        self.read_digest(out);
+        self.reset();
     }
 }
 
@@ -126,6 +254,78 @@ impl<Size: OutputSize> Sha<'_, Size> {
         });
     }
 
+    // Safety: the memory region must be valid for reads.
+    //
+    // Addresses are not turned into slices, as flash starts at address 0.
+    unsafe fn update_raw(&mut self, mut address: usize, mut len: usize) -> Result<()> {
+        // complete a partially filled block first
+        let pos = self.buffer.get_pos();
+        let head = if pos != 0 {
+            min(BLOCK_LEN - pos, len)
+        } else {
+            0
+        };
+        self.update_volatile(address, head);
+        address += head;
+        len -= head;
+
+        if address % 4 != 0 {
+            self.update_volatile(address, len);
+            return Ok(());
+        }
+
+        let blocks = len / BLOCK_LEN;
+        for chunk in (0..blocks).step_by(MAX_BLOCKS) {
+            let count = min(blocks - chunk, MAX_BLOCKS);
+            Self::process_blocks(self.inner, address + chunk * BLOCK_LEN, count)?;
+        }
+        self.len += ((blocks * BLOCK_LEN) as u64) << 3;
+        address += blocks * BLOCK_LEN;
+        len -= blocks * BLOCK_LEN;
+
+        self.update_volatile(address, len);
+        Ok(())
+    }
+
+    // hash memory via the CPU, for parts that are not whole aligned blocks
+    unsafe fn update_volatile(&mut self, address: usize, len: usize) {
+        let mut chunk = [0u8; BLOCK_LEN];
+        for offset in (0..len).step_by(BLOCK_LEN) {
+            let n = min(len - offset, BLOCK_LEN);
+            for (i, byte) in chunk[..n].iter_mut().enumerate() {
+                *byte = core::ptr::read_volatile((address + offset + i) as *const u8);
+            }
+            self.update(&chunk[..n]);
+        }
+    }
+
+    fn process_blocks(
+        peripheral: &mut Hashcrypt<Enabled>,
+        address: usize,
+        count: usize,
+    ) -> Result<()> {
+        while peripheral.raw.status.read().waiting().is_not_waiting() {
+            continue;
+        }
+        peripheral
+            .raw
+            .memaddr
+            .write(|w| unsafe { w.bits(address as u32) });
+        peripheral
+            .raw
+            .memctrl
+            .write(|w| unsafe { w.master().enabled().count().bits(count as u16) });
+
+        // COUNT decrements after each block copied, and stops on bus errors
+        while peripheral.raw.memctrl.read().count().bits() != 0 {
+            if peripheral.raw.status.read().error().is_error() {
+                peripheral.raw.status.write(|w| w.error().set_bit());
+                return Err(Error::Bus);
+            }
+        }
+        Ok(())
+    }
+
     // relevant code is ~line 800 in fsl_hashcrypt.c
     fn process_block(peripheral: &mut Hashcrypt<Enabled>, input: &GenericArray<u8, BlockSize>) {
         // input must be word-aligned
@@ -151,6 +351,14 @@ impl<Size: OutputSize> Sha<'_, Size> {
             continue;
         }
     }
+
+    // cf `hashcrypt_get_data` ~line 315 of `fsl_hashcrypt.c`
+    fn read_digest(&self, out: &mut Output<Self>) {
+        for i in 0..Size::to_usize() / 4 {
+            out.as_mut_slice()[4 * i..4 * i + 4]
+                .copy_from_slice(&self.inner.raw.digest0[i].read().bits().to_be_bytes());
+        }
+    }
 }
 
 impl<Size: OutputSize> digest::Reset for Sha<'_, Size> {
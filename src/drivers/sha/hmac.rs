@@ -0,0 +1,206 @@
+//! HMAC (RFC 2104), on top of a `digest` implementation such as `Sha256`.
+//!
+//! The SHA engine of HASHCRYPT cannot use hidden keys; only the AES engine
+//! is connected to the PUF. `HmacSha256::new_puf` therefore derives its key
+//! by encrypting a fixed label with the PUF key: the PUF key never leaves the
+//! hardware, but the derived HMAC key is held in RAM, until the `Hmac` is
+//! dropped or released.
+
+use cipher::BlockEncrypt;
+use digest::{
+    core_api::BlockSizeUser, FixedOutput, FixedOutputReset, MacMarker, Output, OutputSizeUser,
+    Reset, Update,
+};
+
+use super::Sha256;
+use crate::{
+    drivers::aes::{Aes, Key, Mode},
+    peripherals::hashcrypt::Hashcrypt,
+    traits::digest::generic_array::{typenum::U32, GenericArray},
+    typestates::init_state::Enabled,
+};
+
+const IPAD: u8 = 0x36;
+const OPAD: u8 = 0x5c;
+
+// encrypted with the PUF key to derive the key of `HmacSha256::new_puf`
+const PUF_LABEL: [u8; 32] = *b"lpc55-hal HMAC-SHA256 key\0\0\0\0\0\0\0";
+
+// overwrite key material, in a way the compiler does not elide
+fn clear(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// HMAC-SHA256 using the HASHCRYPT peripheral.
+pub type HmacSha256<'a> = Hmac<Sha256<'a>>;
+
+pub struct Hmac<D: BlockSizeUser> {
+    digest: D,
+    // the key, padded to the block size
+    key: GenericArray<u8, D::BlockSize>,
+}
+
+impl<D> Hmac<D>
+where
+    D: BlockSizeUser + Update + FixedOutputReset,
+{
+    pub fn new(mut digest: D, key: &[u8]) -> Self {
+        let mut padded = GenericArray::<u8, D::BlockSize>::default();
+        if key.len() > padded.len() {
+            digest.update(key);
+            let mut hashed = digest.finalize_fixed_reset();
+            padded[..hashed.len()].copy_from_slice(&hashed);
+            clear(&mut hashed);
+        } else {
+            padded[..key.len()].copy_from_slice(key);
+        }
+
+        let mut hmac = Hmac {
+            digest,
+            key: padded,
+        };
+        hmac.start();
+        hmac
+    }
+
+    pub fn release(self) -> D {
+        let mut hmac = core::mem::ManuallyDrop::new(self);
+        clear(&mut hmac.key);
+        // `hmac` is not dropped, so `digest` is moved out only once
+        unsafe { core::ptr::read(&hmac.digest) }
+    }
+
+    fn start(&mut self) {
+        Reset::reset(&mut self.digest);
+        self.update_padded_key(IPAD);
+    }
+
+    fn update_padded_key(&mut self, pad: u8) {
+        let mut block = self.key.clone();
+        for byte in block.iter_mut() {
+            *byte ^= pad;
+        }
+        self.digest.update(&block);
+        clear(&mut block);
+    }
+
+    fn finish(&mut self) -> Output<D> {
+        let inner = self.digest.finalize_fixed_reset();
+        self.update_padded_key(OPAD);
+        self.digest.update(&inner);
+        self.digest.finalize_fixed_reset()
+    }
+}
+
+impl<'a> HmacSha256<'a> {
+    /// HMAC-SHA256 with a key derived from the PUF key (256 bits) in
+    /// the hidden key slot of HASHCRYPT, see module documentation.
+    pub fn new_puf(hashcrypt: &'a mut Hashcrypt<Enabled>) -> Self {
+        let mut blocks = [GenericArray::default(), GenericArray::default()];
+        blocks[0].copy_from_slice(&PUF_LABEL[..16]);
+        blocks[1].copy_from_slice(&PUF_LABEL[16..]);
+        Aes::<U32>::new(hashcrypt, Key::Puf, Mode::Encrypt).encrypt_blocks(&mut blocks);
+
+        let mut key = [0u8; 32];
+        key[..16].copy_from_slice(&blocks[0]);
+        key[16..].copy_from_slice(&blocks[1]);
+        let hmac = Hmac::new(Sha256::new(hashcrypt), &key);
+
+        clear(&mut key);
+        for block in blocks.iter_mut() {
+            clear(block);
+        }
+        hmac
+    }
+}
+
+impl<D: BlockSizeUser> Drop for Hmac<D> {
+    fn drop(&mut self) {
+        clear(&mut self.key);
+    }
+}
+
+impl<D> OutputSizeUser for Hmac<D>
+where
+    D: BlockSizeUser + OutputSizeUser,
+{
+    type OutputSize = D::OutputSize;
+}
+
+impl<D: BlockSizeUser> MacMarker for Hmac<D> {}
+
+impl<D> Update for Hmac<D>
+where
+    D: BlockSizeUser + Update,
+{
+    fn update(&mut self, data: &[u8]) {
+        self.digest.update(data);
+    }
+}
+
+impl<D> FixedOutput for Hmac<D>
+where
+    D: BlockSizeUser + Update + FixedOutputReset,
+{
+    fn finalize_into(mut self, out: &mut Output<Self>) {
+        out.copy_from_slice(&self.finish());
+    }
+}
+
+impl<D> Reset for Hmac<D>
+where
+    D: BlockSizeUser + Update + FixedOutputReset,
+{
+    fn reset(&mut self) {
+        self.start();
+    }
+}
+
+impl<D> FixedOutputReset for Hmac<D>
+where
+    D: BlockSizeUser + Update + FixedOutputReset,
+{
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        out.copy_from_slice(&self.finish());
+        self.start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digest::Mac;
+    use hex_literal::hex;
+
+    // test cases 1, 2 and 6 of RFC 4231
+    #[test]
+    fn rfc4231() {
+        let cases: [(&[u8], &[u8], [u8; 32]); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                hex!("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                hex!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                hex!("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+            ),
+        ];
+        for (key, data, expected) in cases.iter() {
+            let mut hmac = Hmac::new(sha2::Sha256::default(), key);
+            Mac::update(&mut hmac, data);
+            assert_eq!(hmac.finalize_fixed_reset().as_slice(), expected);
+
+            Mac::update(&mut hmac, data);
+            assert!(hmac.verify_slice(expected).is_ok());
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! SHA-384 and SHA-512 (FIPS 180-4), in software.
+//!
+//! HASHCRYPT only implements SHA-1 and SHA-256. These implement the same
+//! `digest` traits as the hardware `Sha`, so callers can be generic over the hash.
+
+use core::{convert::TryInto, marker::PhantomData};
+
+use block_buffer::{BlockBuffer, Eager};
+use digest::{
+    core_api::BlockSizeUser, FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser,
+    Reset, Update,
+};
+
+use crate::traits::digest::generic_array::{
+    typenum::{U128, U48, U64},
+    GenericArray,
+};
+
+type BlockSize = U128;
+
+// prevent implementing OutputSize for anything other than the valid sizes
+mod sealed {
+    use crate::traits::digest::generic_array::ArrayLength;
+    pub trait OutputSize: ArrayLength<u8> {
+        const INITIAL_STATE: [u64; 8];
+    }
+
+    impl OutputSize for super::U48 {
+        const INITIAL_STATE: [u64; 8] = [
+            0xcbbb9d5dc1059ed8,
+            0x629a292a367cd507,
+            0x9159015a3070dd17,
+            0x152fecd8f70e5939,
+            0x67332667ffc00b31,
+            0x8eb44a8768581511,
+            0xdb0c2e0d64f98fa7,
+            0x47b5481dbefa4fa4,
+        ];
+    }
+
+    impl OutputSize for super::U64 {
+        const INITIAL_STATE: [u64; 8] = [
+            0x6a09e667f3bcc908,
+            0xbb67ae8584caa73b,
+            0x3c6ef372fe94f82b,
+            0xa54ff53a5f1d36f1,
+            0x510e527fade682d1,
+            0x9b05688c2b3e6c1f,
+            0x1f83d9abfb41bd6b,
+            0x5be0cd19137e2179,
+        ];
+    }
+}
+
+use sealed::OutputSize;
+
+const K: [u64; 80] = [
+    0x428a2f98d728ae22,
+    0x7137449123ef65cd,
+    0xb5c0fbcfec4d3b2f,
+    0xe9b5dba58189dbbc,
+    0x3956c25bf348b538,
+    0x59f111f1b605d019,
+    0x923f82a4af194f9b,
+    0xab1c5ed5da6d8118,
+    0xd807aa98a3030242,
+    0x12835b0145706fbe,
+    0x243185be4ee4b28c,
+    0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f,
+    0x80deb1fe3b1696b1,
+    0x9bdc06a725c71235,
+    0xc19bf174cf692694,
+    0xe49b69c19ef14ad2,
+    0xefbe4786384f25e3,
+    0x0fc19dc68b8cd5b5,
+    0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275,
+    0x4a7484aa6ea6e483,
+    0x5cb0a9dcbd41fbd4,
+    0x76f988da831153b5,
+    0x983e5152ee66dfab,
+    0xa831c66d2db43210,
+    0xb00327c898fb213f,
+    0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2,
+    0xd5a79147930aa725,
+    0x06ca6351e003826f,
+    0x142929670a0e6e70,
+    0x27b70a8546d22ffc,
+    0x2e1b21385c26c926,
+    0x4d2c6dfc5ac42aed,
+    0x53380d139d95b3df,
+    0x650a73548baf63de,
+    0x766a0abb3c77b2a8,
+    0x81c2c92e47edaee6,
+    0x92722c851482353b,
+    0xa2bfe8a14cf10364,
+    0xa81a664bbc423001,
+    0xc24b8b70d0f89791,
+    0xc76c51a30654be30,
+    0xd192e819d6ef5218,
+    0xd69906245565a910,
+    0xf40e35855771202a,
+    0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8,
+    0x1e376c085141ab53,
+    0x2748774cdf8eeb99,
+    0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63,
+    0x4ed8aa4ae3418acb,
+    0x5b9cca4f7763e373,
+    0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc,
+    0x78a5636f43172f60,
+    0x84c87814a1f0ab72,
+    0x8cc702081a6439ec,
+    0x90befffa23631e28,
+    0xa4506cebde82bde9,
+    0xbef9a3f7b2c67915,
+    0xc67178f2e372532b,
+    0xca273eceea26619c,
+    0xd186b8c721c0c207,
+    0xeada7dd6cde0eb1e,
+    0xf57d4f7fee6ed178,
+    0x06f067aa72176fba,
+    0x0a637dc5a2c898a6,
+    0x113f9804bef90dae,
+    0x1b710b35131c471b,
+    0x28db77f523047d84,
+    0x32caab7b40c72493,
+    0x3c9ebe0a15c9bebc,
+    0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6,
+    0x597f299cfc657e2a,
+    0x5fcb6fab3ad6faec,
+    0x6c44198c4a475817,
+];
+
+pub struct SoftSha<Size: OutputSize> {
+    state: [u64; 8],
+    buffer: BlockBuffer<BlockSize, Eager>,
+    len: u128,
+    size: PhantomData<Size>,
+}
+
+pub type Sha384 = SoftSha<U48>;
+pub type Sha512 = SoftSha<U64>;
+
+impl<Size: OutputSize> SoftSha<Size> {
+    pub fn new() -> Self {
+        Self {
+            state: Size::INITIAL_STATE,
+            buffer: Default::default(),
+            len: 0,
+            size: PhantomData,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn finish(&mut self, out: &mut Output<Self>) {
+        let state = &mut self.state;
+        self.buffer
+            .len128_padding_be(self.len, |block| compress(state, block));
+        for (chunk, word) in out.chunks_mut(8).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+}
+
+impl<Size: OutputSize> Default for SoftSha<Size> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the `digest` traits
+
+impl<Size: OutputSize> HashMarker for SoftSha<Size> {}
+
+impl<Size: OutputSize> OutputSizeUser for SoftSha<Size> {
+    type OutputSize = Size;
+}
+
+impl<Size: OutputSize> BlockSizeUser for SoftSha<Size> {
+    type BlockSize = BlockSize;
+}
+
+impl<Size: OutputSize> Update for SoftSha<Size> {
+    fn update(&mut self, data: &[u8]) {
+        self.len += (data.len() as u128) << 3;
+        let state = &mut self.state;
+        self.buffer.digest_blocks(data, |blocks| {
+            for block in blocks {
+                compress(state, block);
+            }
+        });
+    }
+}
+
+impl<Size: OutputSize> FixedOutput for SoftSha<Size> {
+    fn finalize_into(mut self, out: &mut Output<Self>) {
+        self.finish(out);
+    }
+}
+
+impl<Size: OutputSize> FixedOutputReset for SoftSha<Size> {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        self.finish(out);
+        self.reset();
+    }
+}
+
+impl<Size: OutputSize> Reset for SoftSha<Size> {
+    fn reset(&mut self) {
+        self.reset();
+    }
+}
+
+// the actual implementation
+
+// the message schedule is kept in a ring of 16 words, to save stack
+fn compress(state: &mut [u64; 8], block: &GenericArray<u8, BlockSize>) {
+    let mut w = [0u64; 16];
+    for (word, chunk) in w.iter_mut().zip(block.chunks_exact(8)) {
+        *word = u64::from_be_bytes(chunk.try_into().unwrap());
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (i, k) in K.iter().enumerate() {
+        if i >= 16 {
+            let w15 = w[(i + 1) & 15];
+            let w2 = w[(i + 14) & 15];
+            let s0 = w15.rotate_right(1) ^ w15.rotate_right(8) ^ (w15 >> 7);
+            let s1 = w2.rotate_right(19) ^ w2.rotate_right(61) ^ (w2 >> 6);
+            w[i & 15] = w[i & 15]
+                .wrapping_add(s0)
+                .wrapping_add(w[(i + 9) & 15])
+                .wrapping_add(s1);
+        }
+
+        let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(*k)
+            .wrapping_add(w[i & 15]);
+        let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, value) in state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(*value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digest::Digest;
+    use hex_literal::hex;
+
+    // FIPS 180-4 examples
+    #[test]
+    fn abc() {
+        assert_eq!(
+            Sha384::digest(b"abc").as_slice(),
+            hex!(
+                "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed"
+                "8086072ba1e7cc2358baeca134c825a7"
+            )
+        );
+        assert_eq!(
+            Sha512::digest(b"abc").as_slice(),
+            hex!(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a"
+                "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )
+        );
+    }
+
+    #[test]
+    fn against_sha2() {
+        let data: [u8; 300] = core::array::from_fn(|i| i as u8);
+        for len in 0..data.len() {
+            assert_eq!(
+                Sha384::digest(&data[..len]),
+                sha2::Sha384::digest(&data[..len])
+            );
+            assert_eq!(
+                Sha512::digest(&data[..len]),
+                sha2::Sha512::digest(&data[..len])
+            );
+        }
+    }
+}
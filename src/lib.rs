@@ -1,5 +1,7 @@
 #![no_std]
 #![allow(static_mut_refs)]
+// `is_multiple_of` would need Rust 1.87
+#![allow(clippy::manual_is_multiple_of)]
 
 //! This HAL takes a layered approach.
 //!
@@ -1,7 +1,160 @@
 use core::result::Result;
 // use cortex_m_semihosting::{heprint,heprintln};
-use crate::{drivers::clocks::Clocks, typestates::init_state};
-use core::ptr::copy_nonoverlapping;
+use crate::{
+    drivers::{clocks::Clocks, sha::Sha256},
+    peripherals::{
+        prince::Region,
+        puf::{ActivationCode, KeyCode, KeySize, ACTIVATION_CODE_LEN},
+    },
+    traits::digest::{generic_array::typenum::U52, FixedOutputReset, Update},
+    typestates::{
+        init_state,
+        seal_state::{SealState, Sealed, Unsealed},
+    },
+};
+use core::{marker::PhantomData, ptr::copy_nonoverlapping};
+
+/// PFR write error
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Status code returned by the boot ROM
+    Rom(u32),
+    /// CFPA version is not newer than the latest one
+    StaleVersion,
+    /// A monotonic CFPA counter would decrease
+    Rollback,
+    /// Upper half of a debug configuration is not the inverse of its lower half
+    InvalidDebugCfg,
+    /// Rollback counter is at its maximum
+    CounterExhausted,
+}
+
+macro_rules! bitfields {
+    ($(#[$attr:meta])* $name:ident {
+        $($(#[$doc:meta])* $get:ident, $set:ident: $offset:expr, $width:expr;)*
+    }) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug, Default, PartialEq)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            $(
+                $(#[$doc])*
+                pub fn $get(&self) -> u32 {
+                    (self.0 >> $offset) & ((1 << $width) - 1)
+                }
+
+                pub fn $set(self, value: u32) -> Self {
+                    let mask = ((1 << $width) - 1) << $offset;
+                    $name((self.0 & !mask) | ((value << $offset) & mask))
+                }
+            )*
+        }
+    };
+}
+
+bitfields!(
+    /// `Cmpa::boot_cfg`
+    BootCfg {
+        /// ISP interface entered on boot failure, 0 for auto-detection
+        default_isp_mode, with_default_isp_mode: 4, 3;
+        /// Core clock during boot: 0 from NMPA, 1 at 48 MHz, 2 at 96 MHz
+        boot_speed, with_boot_speed: 7, 2;
+        /// GPIO (port << 5 | pin) asserted on boot failure
+        boot_failure_pin, with_boot_failure_pin: 24, 8;
+    }
+);
+
+bitfields!(
+    /// `Cmpa::secure_boot_cfg`, for the 2-bit fields any non-zero value enables
+    SecureBootCfg {
+        /// Use 4096 bit RSA keys only
+        rsa4k, with_rsa4k: 0, 2;
+        /// Include NXP area in the DICE computation
+        dice_enc_nxp_cfg, with_dice_enc_nxp_cfg: 2, 2;
+        /// Include customer factory area in the DICE computation
+        dice_cust_cfg, with_dice_cust_cfg: 4, 2;
+        /// Skip the DICE computation
+        skip_dice, with_skip_dice: 6, 2;
+        /// TrustZone image type: 0 from header, 1 disabled, 2 enabled, 3 preset
+        tzm_image_type, with_tzm_image_type: 8, 2;
+        /// Block the PUF `set_key` command
+        block_set_key, with_block_set_key: 10, 2;
+        /// Block the PUF `enroll` command
+        block_enroll, with_block_enroll: 12, 2;
+        /// Include the security epoch in the DICE computation
+        dice_inc_sec_epoch, with_dice_inc_sec_epoch: 14, 2;
+        /// Only boot signed images
+        sec_boot_en, with_sec_boot_en: 30, 2;
+    }
+);
+
+bitfields!(
+    /// `Cmpa::{dcfg_pin, dcfg_dflt}` and `Cfpa::{dcfg_ns_pin, dcfg_ns_dflt}`
+    ///
+    /// The upper half must be the inverse of the lower half, see `inverted`.
+    DebugCfg {
+        /// Non-secure non-invasive debug
+        niden, with_niden: 0, 1;
+        /// Non-secure debug
+        dbgen, with_dbgen: 1, 1;
+        /// Secure non-invasive debug
+        spniden, with_spniden: 2, 1;
+        /// Secure debug
+        spiden, with_spiden: 3, 1;
+        /// JTAG TAP
+        tapen, with_tapen: 4, 1;
+        /// CPU1 debug
+        cpu1_dbgen, with_cpu1_dbgen: 5, 1;
+        /// ISP boot command
+        isp_cmd_en, with_isp_cmd_en: 6, 1;
+        /// FA (fault analysis) mode command
+        fa_cmd_en, with_fa_cmd_en: 7, 1;
+        /// Flash mass erase command
+        me_cmd_en, with_me_cmd_en: 8, 1;
+        /// CPU1 non-invasive debug
+        cpu1_niden, with_cpu1_niden: 9, 1;
+        /// Check the UUID in debug credentials
+        uuid_check, with_uuid_check: 15, 1;
+    }
+);
+
+impl DebugCfg {
+    /// Set the upper half to the inverse of the lower half.
+    pub fn inverted(self) -> Self {
+        DebugCfg((self.0 & 0xffff) | (!self.0 << 16))
+    }
+
+    /// Whether the upper half is the inverse of the lower half, or the configuration is blank.
+    pub fn is_valid(&self) -> bool {
+        self.0 == 0 || self.0 >> 16 == !self.0 & 0xffff
+    }
+}
+
+bitfields!(
+    /// `Cfpa::rotkh_revoke`: 1 enables a root of trust key, 2 or 3 revoke it
+    RotkhRevoke {
+        rotk0_en, with_rotk0_en: 0, 2;
+        rotk1_en, with_rotk1_en: 2, 2;
+        rotk2_en, with_rotk2_en: 4, 2;
+    }
+);
+
+// digest over all but the last 32 bytes of a page
+fn page_digest(page: &[u8; 512], sha256: &mut Sha256<'_>) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    sha256.update(&page[..480]);
+    digest.copy_from_slice(&sha256.finalize_fixed_reset());
+    digest
+}
+
+fn check_debug_cfgs(cfgs: &[u32]) -> Result<(), Error> {
+    if cfgs.iter().all(|cfg| DebugCfg(*cfg).is_valid()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidDebugCfg)
+    }
+}
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum KeyType {
@@ -50,6 +203,35 @@ pub struct Cfpa {
 }
 
 impl Cfpa {
+    pub fn as_bytes(&self) -> &[u8; 512] {
+        unsafe { &*(self as *const Self as *const [u8; 512]) }
+    }
+
+    pub fn sha256(&self) -> &[u8; 32] {
+        &self.sha256
+    }
+
+    // `self` may replace `latest`
+    fn check_update(&self, latest: &Cfpa) -> Result<(), Error> {
+        if self.version <= latest.version {
+            return Err(Error::StaleVersion);
+        }
+        let (rotkh_revoke, latest_rotkh_revoke) = (
+            RotkhRevoke(self.rotkh_revoke),
+            RotkhRevoke(latest.rotkh_revoke),
+        );
+        if self.secure_fw_version < latest.secure_fw_version
+            || self.ns_fw_version < latest.ns_fw_version
+            || self.image_key_revoke < latest.image_key_revoke
+            || rotkh_revoke.rotk0_en() < latest_rotkh_revoke.rotk0_en()
+            || rotkh_revoke.rotk1_en() < latest_rotkh_revoke.rotk1_en()
+            || rotkh_revoke.rotk2_en() < latest_rotkh_revoke.rotk2_en()
+        {
+            return Err(Error::Rollback);
+        }
+        check_debug_cfgs(&[self.dcfg_ns_pin, self.dcfg_ns_dflt])
+    }
+
     /// Check if everything has been done to set up a particular HW key.
     pub fn key_provisioned(&self, key_type: KeyType) -> bool {
         match key_type {
@@ -92,15 +274,300 @@ pub struct Cmpa {
     sha256: [u8; 32],
 }
 
-// This compile time guarantees that Cmpa and Cfpa are 512 bytes.
+impl Cmpa {
+    pub fn as_bytes(&self) -> &[u8; 512] {
+        unsafe { &*(self as *const Self as *const [u8; 512]) }
+    }
+
+    pub fn sha256(&self) -> &[u8; 32] {
+        &self.sha256
+    }
+}
+
+/// Builder of a CFPA page, to write with `Pfr::write_cfpa`.
+pub struct CfpaBuilder {
+    cfpa: Cfpa,
+}
+
+impl CfpaBuilder {
+    /// Start from `cfpa`, usually `Pfr::read_latest_cfpa`.
+    pub fn new(cfpa: &Cfpa) -> Self {
+        CfpaBuilder { cfpa: *cfpa }
+    }
+
+    pub fn secure_fw_version(mut self, version: u32) -> Self {
+        self.cfpa.secure_fw_version = version;
+        self
+    }
+
+    pub fn ns_fw_version(mut self, version: u32) -> Self {
+        self.cfpa.ns_fw_version = version;
+        self
+    }
+
+    pub fn image_key_revoke(mut self, image_key_revoke: u32) -> Self {
+        self.cfpa.image_key_revoke = image_key_revoke;
+        self
+    }
+
+    pub fn rotkh_revoke(mut self, rotkh_revoke: RotkhRevoke) -> Self {
+        self.cfpa.rotkh_revoke = rotkh_revoke.0;
+        self
+    }
+
+    pub fn dcfg_ns_pin(mut self, cfg: DebugCfg) -> Self {
+        self.cfpa.dcfg_ns_pin = cfg.inverted().0;
+        self
+    }
+
+    pub fn dcfg_ns_dflt(mut self, cfg: DebugCfg) -> Self {
+        self.cfpa.dcfg_ns_dflt = cfg.inverted().0;
+        self
+    }
+
+    pub fn customer_data(mut self, data: &[u8; 224]) -> Self {
+        self.cfpa.customer_data = *data;
+        self
+    }
+
+    /// Bump the version and compute the digest.
+    pub fn build(mut self, sha256: &mut Sha256<'_>) -> Cfpa {
+        self.cfpa.version = self.cfpa.version.wrapping_add(1);
+        self.cfpa.sha256 = page_digest(self.cfpa.as_bytes(), sha256);
+        self.cfpa
+    }
+}
+
+/// Builder of a CMPA page, to write with `Pfr::write_cmpa`.
+///
+/// The page is only sealed after the explicit `seal` step.
+pub struct CmpaBuilder<Seal = Unsealed> {
+    cmpa: Cmpa,
+    _seal: PhantomData<Seal>,
+}
+
+impl CmpaBuilder {
+    /// Start from `cmpa`, usually `Pfr::read_cmpa`.
+    pub fn new(cmpa: &Cmpa) -> Self {
+        CmpaBuilder {
+            cmpa: *cmpa,
+            _seal: PhantomData,
+        }
+    }
+
+    /// Seal CMPA when writing it.  This can't be undone, CMPA can't be written anymore.
+    pub fn seal(self) -> CmpaBuilder<Sealed> {
+        CmpaBuilder {
+            cmpa: self.cmpa,
+            _seal: PhantomData,
+        }
+    }
+}
+
+impl<Seal: SealState> CmpaBuilder<Seal> {
+    pub fn boot_cfg(mut self, cfg: BootCfg) -> Self {
+        self.cmpa.boot_cfg = cfg.0;
+        self
+    }
+
+    pub fn spi_flash_cfg(mut self, cfg: u32) -> Self {
+        self.cmpa.spi_flash_cfg = cfg;
+        self
+    }
+
+    pub fn usb_id(mut self, vid: u16, pid: u16) -> Self {
+        self.cmpa.usb_vid = vid;
+        self.cmpa.usb_pid = pid;
+        self
+    }
+
+    pub fn sdio_cfg(mut self, cfg: u32) -> Self {
+        self.cmpa.sdio_cfg = cfg;
+        self
+    }
+
+    pub fn dcfg_pin(mut self, cfg: DebugCfg) -> Self {
+        self.cmpa.dcfg_pin = cfg.inverted().0;
+        self
+    }
+
+    pub fn dcfg_dflt(mut self, cfg: DebugCfg) -> Self {
+        self.cmpa.dcfg_dflt = cfg.inverted().0;
+        self
+    }
+
+    pub fn dap_vendor_usage(mut self, usage: u32) -> Self {
+        self.cmpa.dap_vendor_usage = usage;
+        self
+    }
+
+    pub fn secure_boot_cfg(mut self, cfg: SecureBootCfg) -> Self {
+        self.cmpa.secure_boot_cfg = cfg.0;
+        self
+    }
+
+    pub fn prince_base_addr(mut self, addr: u32) -> Self {
+        self.cmpa.prince_base_addr = addr;
+        self
+    }
+
+    /// Subregions of `region` that the ROM enables PRINCE for at boot.
+    pub fn prince_sr(mut self, region: Region, sr: u32) -> Self {
+        self.cmpa.prince_sr[region as usize] = sr;
+        self
+    }
+
+    pub fn rotkh(mut self, rotkh: &[u8; 32]) -> Self {
+        self.cmpa.rotkh = *rotkh;
+        self
+    }
+
+    pub fn customer_data(mut self, data: &[u8; 224]) -> Self {
+        self.cmpa.customer_data = *data;
+        self
+    }
+
+    /// Compute the digest, which the ROM checks once CMPA is sealed.
+    pub fn build(mut self, sha256: &mut Sha256<'_>) -> Cmpa {
+        self.cmpa.sha256 = page_digest(self.cmpa.as_bytes(), sha256);
+        self.cmpa
+    }
+}
+
+/// Monotonic counters of CFPA, e.g. for a bootloader to reject downgraded images.
+///
+/// `rotkh_revoke` is a bit field, revoke keys with `CfpaBuilder::rotkh_revoke`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RollbackCounter {
+    SecureFwVersion,
+    NsFwVersion,
+    ImageKeyRevoke,
+}
+
+impl RollbackCounter {
+    fn get(self, cfpa: &Cfpa) -> u32 {
+        match self {
+            RollbackCounter::SecureFwVersion => cfpa.secure_fw_version,
+            RollbackCounter::NsFwVersion => cfpa.ns_fw_version,
+            RollbackCounter::ImageKeyRevoke => cfpa.image_key_revoke,
+        }
+    }
+
+    /// Value in the latest CFPA.
+    pub fn read(self, pfr: &mut Pfr<init_state::Enabled>) -> Result<u32, Error> {
+        let latest = pfr.read_latest_cfpa().map_err(Error::Rom)?;
+        Ok(self.get(&latest))
+    }
+
+    /// Check that `value` (e.g. of an image) is not below the counter.
+    pub fn check(self, pfr: &mut Pfr<init_state::Enabled>, value: u32) -> Result<(), Error> {
+        if value < self.read(pfr)? {
+            Err(Error::Rollback)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Raise the counter to `value` with a single CFPA write, refusing to decrease it.
+    pub fn advance(
+        self,
+        pfr: &mut Pfr<init_state::Enabled>,
+        value: u32,
+        sha256: &mut Sha256<'_>,
+    ) -> Result<(), Error> {
+        let latest = pfr.read_latest_cfpa().map_err(Error::Rom)?;
+        let current = self.get(&latest);
+        if value < current {
+            return Err(Error::Rollback);
+        }
+        if value == current {
+            return Ok(());
+        }
+
+        let builder = CfpaBuilder::new(&latest);
+        let builder = match self {
+            RollbackCounter::SecureFwVersion => builder.secure_fw_version(value),
+            RollbackCounter::NsFwVersion => builder.ns_fw_version(value),
+            RollbackCounter::ImageKeyRevoke => builder.image_key_revoke(value),
+        };
+        pfr.write_cfpa(&builder.build(sha256))
+    }
+
+    /// Increment the counter, returning its new value.
+    pub fn increment(
+        self,
+        pfr: &mut Pfr<init_state::Enabled>,
+        sha256: &mut Sha256<'_>,
+    ) -> Result<u32, Error> {
+        let value = self
+            .read(pfr)?
+            .checked_add(1)
+            .ok_or(Error::CounterExhausted)?;
+        self.advance(pfr, value, sha256)?;
+        Ok(value)
+    }
+}
+
+// cf. the key store area of CMPA in the UM
+const KEY_STORE_MARKER: u32 = 0x9595_9595;
+const KEY_CODE_MARKER: u32 = 0x5959_5959;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct KeyCodeSlot {
+    header: u32,
+    key_code: [u8; 52],
+}
+
+/// Key store area of CMPA: the PUF activation code, and a key code per `KeyType`.
+///
+/// The boot ROM uses it to start the PUF, written with `Pfr::write_key_store`.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct KeyStore {
+    header: u32,
+    pub puf_discharge_time_in_ms: u32,
+    activation_code: [u8; ACTIVATION_CODE_LEN],
+    key_codes: [KeyCodeSlot; 6],
+}
+
+impl KeyStore {
+    /// Key store for `activation_code`, without key codes.
+    pub fn new(activation_code: &ActivationCode) -> Self {
+        KeyStore {
+            header: KEY_STORE_MARKER,
+            puf_discharge_time_in_ms: 0,
+            activation_code: *activation_code.as_bytes(),
+            key_codes: [KeyCodeSlot {
+                header: 0,
+                key_code: [0; 52],
+            }; 6],
+        }
+    }
+
+    /// Store the key code of `key_type`, only keys of up to 256 bits fit.
+    pub fn set_key_code<Size: KeySize<KeyCodeSize = U52>>(
+        &mut self,
+        key_type: KeyType,
+        key_code: &KeyCode<Size>,
+    ) {
+        let slot = &mut self.key_codes[key_type as usize];
+        slot.header = KEY_CODE_MARKER;
+        slot.key_code.copy_from_slice(key_code.as_bytes());
+    }
+}
+
+// This compile time guarantees that Cmpa and Cfpa are 512 bytes,
+// and the key store 3 pages.
 const _: () = {
     assert!(size_of::<Cmpa>() == 512);
     assert!(size_of::<Cfpa>() == 512);
+    assert!(size_of::<KeyStore>() == 3 * 512);
 };
 
 // #define BOOTLOADER_API_TREE_POINTER (bootloader_tree_t*) 0x130010f0
 #[repr(C)]
-struct BootloaderTree {
+pub(crate) struct BootloaderTree {
     // All this does is a soft reset.
     run_bootloader: extern "C" fn(arg: &u32) -> (),
 
@@ -108,7 +575,7 @@ struct BootloaderTree {
     copyright: *const char,
     reserved0: u32,
 
-    flash_driver: &'static FlashDriverInterface,
+    pub(crate) flash_driver: &'static FlashDriverInterface,
 
     // don't need these.
     reserved_kb_interface: u32,
@@ -118,24 +585,24 @@ struct BootloaderTree {
 
 #[allow(non_snake_case)]
 #[repr(C)]
-struct FlashDriverInterface {
+pub(crate) struct FlashDriverInterface {
     version: u32,
     flash_init: unsafe extern "C" fn(config: &mut FlashConfig) -> u32,
-    flash_erase: unsafe extern "C" fn(
+    pub(crate) flash_erase: unsafe extern "C" fn(
         config: &mut FlashConfig,
         start: u32,
         length_in_bytes: u32,
         key: u32,
     ) -> u32,
-    flash_program: unsafe extern "C" fn(
+    pub(crate) flash_program: unsafe extern "C" fn(
         config: &mut FlashConfig,
         start: u32,
         src: *const u8,
         length_in_bytes: u32,
     ) -> u32,
-    flash_verify_erase:
+    pub(crate) flash_verify_erase:
         unsafe extern "C" fn(config: &mut FlashConfig, start: u32, length_in_bytes: u32) -> u32,
-    flash_verify_program: unsafe extern "C" fn(
+    pub(crate) flash_verify_program: unsafe extern "C" fn(
         config: &mut FlashConfig,
         start: u32,
         length_in_bytes: u32,
@@ -144,7 +611,7 @@ struct FlashDriverInterface {
         failed_data: &mut u32,
     ) -> u32,
 
-    flash_get_property:
+    pub(crate) flash_get_property:
         unsafe extern "C" fn(config: &mut FlashConfig, tag: u32, value: &mut u32) -> u32,
     reserved: [u32; 3],
 
@@ -163,8 +630,8 @@ struct FlashDriverInterface {
         len: u32,
     ) -> u32,
 
-    // TODO
-    ffr_keystore_write: unsafe extern "C" fn(config: &mut FlashConfig) -> u32,
+    ffr_keystore_write:
+        unsafe extern "C" fn(config: &mut FlashConfig, key_store: *const KeyStore) -> u32,
     ffr_keystore_get_ac:
         unsafe extern "C" fn(config: &mut FlashConfig, activation_code: *mut u8) -> u32,
     ffr_keystore_get_kc:
@@ -180,6 +647,7 @@ struct FlashDriverInterface {
     ) -> u32,
 }
 
+#[derive(Clone)]
 #[repr(C)]
 pub struct FlashFfrConfig {
     pub ffr_block_base: u32,
@@ -189,6 +657,7 @@ pub struct FlashFfrConfig {
     pub cfpa_page_offset: u32,
 }
 
+#[derive(Clone)]
 #[repr(C)]
 pub struct FlashModeConfig {
     sys_freq_in_mhz: u32,
@@ -197,6 +666,7 @@ pub struct FlashModeConfig {
     read_mode: u32,
 }
 
+#[derive(Clone)]
 #[repr(C)]
 pub struct FlashConfig {
     pflash_block_base: u32,
@@ -242,7 +712,7 @@ pub struct Pfr<State = init_state::Unknown> {
     pub _state: State,
 }
 impl<State> Pfr<State> {
-    fn bootloader_api_tree() -> &'static mut BootloaderTree {
+    pub(crate) fn bootloader_api_tree() -> &'static mut BootloaderTree {
         #[allow(clippy::transmute_ptr_to_ref)]
         unsafe {
             core::mem::transmute(0x130010f0u32 as *const ())
@@ -392,17 +862,47 @@ impl Pfr<init_state::Enabled> {
         Ok(*cfpa)
     }
 
-    pub fn write_cfpa(&mut self, cfpa: &Cfpa) -> Result<(), u32> {
+    /// Write CFPA, which must have a newer version than the latest one,
+    /// and must not decrease its firmware versions or revocations.
+    ///
+    /// Use `CfpaBuilder` to bump the version and compute the digest.
+    pub fn write_cfpa(&mut self, cfpa: &Cfpa) -> Result<(), Error> {
+        let latest = self.read_latest_cfpa().map_err(Error::Rom)?;
+        cfpa.check_update(&latest)?;
+
         let ffr_infield_page_write = Self::bootloader_api_tree()
             .flash_driver
             .ffr_infield_page_write;
-        let cfpa_bytes: *const u8 = unsafe { core::mem::transmute(cfpa as *const Cfpa) };
         Self::check_error(unsafe {
-            ffr_infield_page_write(&mut self.flash_config, cfpa_bytes, 512)
-        })?;
+            ffr_infield_page_write(&mut self.flash_config, cfpa.as_bytes().as_ptr(), 512)
+        })
+        .map_err(Error::Rom)?;
         Ok(())
     }
 
+    /// Write CMPA, sealing it if `cmpa` is `CmpaBuilder<Sealed>`.
+    pub fn write_cmpa<Seal: SealState>(
+        &mut self,
+        cmpa: CmpaBuilder<Seal>,
+        sha256: &mut Sha256<'_>,
+    ) -> Result<Cmpa, Error> {
+        let cmpa = cmpa.build(sha256);
+        check_debug_cfgs(&[cmpa.dcfg_pin, cmpa.dcfg_dflt])?;
+
+        let ffr_cust_factory_page_write = Self::bootloader_api_tree()
+            .flash_driver
+            .ffr_cust_factory_page_write;
+        Self::check_error(unsafe {
+            ffr_cust_factory_page_write(
+                &mut self.flash_config,
+                cmpa.as_bytes().as_ptr(),
+                Seal::SEAL,
+            )
+        })
+        .map_err(Error::Rom)?;
+        Ok(cmpa)
+    }
+
     pub fn read_key_code(&mut self, key_type: KeyType) -> Result<[u8; 52], u32> {
         let mut bytes = [0u8; 52];
         let ffr_keystore_get_kc = Self::bootloader_api_tree().flash_driver.ffr_keystore_get_kc;
@@ -414,6 +914,13 @@ impl Pfr<init_state::Enabled> {
         Ok(bytes)
     }
 
+    /// Write the key store area of CMPA.  Not possible anymore once CMPA is sealed.
+    pub fn write_key_store(&mut self, key_store: &KeyStore) -> Result<(), u32> {
+        let ffr_keystore_write = Self::bootloader_api_tree().flash_driver.ffr_keystore_write;
+        Self::check_error(unsafe { ffr_keystore_write(&mut self.flash_config, key_store) })?;
+        Ok(())
+    }
+
     pub fn read_activation_code(&mut self) -> Result<[u8; 1192], u32> {
         let mut ac = [0u8; 1192];
         let ffr_keystore_get_ac = Self::bootloader_api_tree().flash_driver.ffr_keystore_get_ac;
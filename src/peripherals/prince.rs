@@ -7,6 +7,17 @@ pub enum Region {
     Region2,
 }
 
+impl Region {
+    /// The region containing a flash address
+    pub const fn containing(address: usize) -> Self {
+        match address {
+            0..=0x3_ffff => Region::Region0,
+            0x4_0000..=0x7_ffff => Region::Region1,
+            _ => Region::Region2,
+        }
+    }
+}
+
 // crate::wrap_stateful_peripheral!(Rtc, RTC);
 pub struct Prince<State = init_state::Unknown> {
     pub(crate) raw: raw::PRINCE,
@@ -102,6 +113,13 @@ impl Prince<init_state::Enabled> {
         result
     }
 
+    pub fn enable_region_for<R>(&self, region: Region, f: impl FnOnce() -> R) -> R {
+        self.set_region_enable(region, 0xffffffff);
+        let result = f();
+        self.set_region_enable(region, 0x0);
+        result
+    }
+
     pub fn set_region_enable(&self, region: Region, enable: u32) {
         match region {
             Region::Region0 => self.raw.sr_enable0.write(|w| unsafe { w.bits(enable) }),
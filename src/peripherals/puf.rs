@@ -1,4 +1,11 @@
-use crate::{peripherals::syscon::Syscon, raw, typestates::init_state};
+use core::convert::TryInto;
+
+use crate::{
+    peripherals::syscon::Syscon,
+    raw,
+    traits::digest::generic_array::{typenum::Unsigned, GenericArray},
+    typestates::init_state,
+};
 
 // Once a PUF is started, you can generate or derive keys.
 // Check NXP AN2324 for the best explanation.
@@ -22,9 +29,138 @@ pub enum Error {
     CommandFailed,
     /// PUF Command is not allowed
     NotAllowed,
+    /// Key index is not from 0 to 15, or 0 for a key that must be output
+    InvalidKeyIndex,
+    /// Key code has the wrong length, or its header does not match the key size
+    InvalidKeyCode,
+    /// Activation code has the wrong length, or is blank or erased
+    InvalidActivationCode,
+    /// Output buffer is too small for the key
+    BufferTooSmall,
+    /// PUF did not lock after zeroizing
+    ZeroizeFailed,
 }
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Size of an activation code, in bytes
+pub const ACTIVATION_CODE_LEN: usize = 1192;
+
+// key codes are 20 bytes longer than the key, but at least 52 bytes
+mod sealed {
+    use crate::traits::digest::generic_array::{
+        typenum::{consts::*, Maximum, Sum},
+        ArrayLength,
+    };
+
+    pub trait KeySize: ArrayLength<u8> {
+        type KeyCodeSize: ArrayLength<u8>;
+    }
+
+    macro_rules! key_sizes {
+        ($($size:ty),*) => {
+            $(
+                impl KeySize for $size {
+                    type KeyCodeSize = Sum<Maximum<$size, U32>, U20>;
+                }
+            )*
+        };
+    }
+
+    key_sizes!(
+        U8, U16, U24, U32, U40, U48, U56, U64, U72, U80, U88, U96, U104, U112, U120, U128, U136,
+        U144, U152, U160, U168, U176, U184, U192, U200, U208, U216, U224, U232, U240, U248, U256,
+        U264, U272, U280, U288, U296, U304, U312, U320, U328, U336, U344, U352, U360, U368, U376,
+        U384, U392, U400, U408, U416, U424, U432, U440, U448, U456, U464, U472, U480, U488, U496,
+        U504, U512
+    );
+}
+
+pub use sealed::KeySize;
+
+/// Activation code of an enrolled PUF, see `Puf::enroll`.
+///
+/// It must be stored (e.g. in flash) to start the PUF after a reset.
+#[derive(Clone)]
+pub struct ActivationCode([u8; ACTIVATION_CODE_LEN]);
+
+impl ActivationCode {
+    /// Parse an activation code, rejecting blank (all zero) or erased (all 0xFF) data.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; ACTIVATION_CODE_LEN] =
+            bytes.try_into().map_err(|_| Error::InvalidActivationCode)?;
+        if bytes.iter().all(|byte| *byte == 0) || bytes.iter().all(|byte| *byte == 0xff) {
+            return Err(Error::InvalidActivationCode);
+        }
+        Ok(ActivationCode(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ACTIVATION_CODE_LEN] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for ActivationCode {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Key code of a key of `Size` bytes, see `Puf::generate_key` and `Puf::set_key`.
+///
+/// Only the PUF that created it can recover the key, so it can be stored
+/// in the clear, e.g. in flash.
+pub struct KeyCode<Size: KeySize> {
+    bytes: GenericArray<u8, Size::KeyCodeSize>,
+}
+
+impl<Size: KeySize> Clone for KeyCode<Size> {
+    fn clone(&self) -> Self {
+        KeyCode {
+            bytes: self.bytes.clone(),
+        }
+    }
+}
+
+impl<Size: KeySize> KeyCode<Size> {
+    // key size in the header, in 64-bit units, as in KEYSIZE
+    const HEADER_SIZE: u8 = ((Size::USIZE / 8) & 0x3f) as u8;
+
+    /// Parse a key code, checking its length and the key size in its header.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != Size::KeyCodeSize::USIZE {
+            return Err(Error::InvalidKeyCode);
+        }
+        let key_code = KeyCode {
+            bytes: GenericArray::clone_from_slice(bytes),
+        };
+        if key_code.bytes[3] != Self::HEADER_SIZE {
+            return Err(Error::InvalidKeyCode);
+        }
+        Ok(key_code)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // cf `PUF_GetKey` in `fsl_puf.c`
+    /// Key index from the header, 0 for keys that can only be sent to AES or PRINCE
+    pub fn key_index(&self) -> u8 {
+        self.bytes[1] & 0x0f
+    }
+
+    /// Whether the key can be output with `Puf::unwrap`, i.e. has non-zero index.
+    pub fn is_exportable(&self) -> bool {
+        self.key_index() != 0
+    }
+}
+
+impl<Size: KeySize> AsRef<[u8]> for KeyCode<Size> {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
 pub enum KeyDestination {
     AES = 0,
     PRINCE1 = 1,
@@ -82,18 +218,21 @@ impl<T> Puf<init_state::Enabled<T>> {
         Ok(())
     }
 
+    // `data` has the size the command outputs
     fn read_data(&self, data: &mut [u8]) {
         let mut count = 0;
         while self.raw.stat.read().busy().bit_is_set() {
             if self.raw.stat.read().codeoutavail().bit_is_set() {
                 let word = self.raw.codeoutput.read().bits();
-                data[count..count + 4].copy_from_slice(&word.to_ne_bytes());
+                if let Some(bytes) = data.get_mut(count..count + 4) {
+                    bytes.copy_from_slice(&word.to_ne_bytes());
+                }
                 count += 4;
             }
         }
     }
 
-    // key_size: 64-4096 bits.
+    // Size: 8-512 bytes (64-4096 bits), see `KeySize`.
     //      4096 bits, KC size = 532 bytes
     //      64 bits,   KC size = 52 bytes
     //      128 bits,  KC size = 52 bytes
@@ -105,79 +244,160 @@ impl<T> Puf<init_state::Enabled<T>> {
     //  0   : Send to AES or PRINCE IP directly
     //  1-15: Pick a key slot/tag to use for associated key.
     //
-    // key_code: Returned KC
-    //  This is fed to a started PUF to derive a key.
-    pub fn generate_key(&self, key_size: u32, key_index: u8, key_code: &mut [u8]) -> Result<()> {
-        if self.raw.allow.read().allowsetkey().bit_is_clear() {
-            return Err(Error::NotAllowed);
-        }
-        assert!(key_size >= 64);
-        assert!(key_index < 16);
-
-        key_code.fill(0);
-
-        self.raw
-            .keysize
-            .write(|w| unsafe { w.bits((key_size >> 6) & 0x3f) });
-        self.raw
-            .keyindex
-            .write(|w| unsafe { w.bits(((key_index) & 0x0f) as u32) });
+    // Returns the KC, which is fed to a started PUF to derive a key.
+    pub fn generate_key<Size: KeySize>(&self, key_index: u8) -> Result<KeyCode<Size>> {
+        self.configure_key::<Size>(key_index)?;
+        let mut key_code = KeyCode::<Size> {
+            bytes: Default::default(),
+        };
 
         self.raw.ctrl.write(|w| w.generatekey().set_bit());
 
         self.wait_for_cmd()?;
 
-        self.read_data(key_code);
+        self.read_data(&mut key_code.bytes);
 
         self.check_success()?;
-        Ok(())
+        Ok(key_code)
+    }
+
+    // Like `generate_key`, for a key given by the user.
+    pub fn set_key<Size: KeySize>(
+        &self,
+        key_index: u8,
+        user_key: &GenericArray<u8, Size>,
+    ) -> Result<KeyCode<Size>> {
+        self.configure_key::<Size>(key_index)?;
+        let mut key_code = KeyCode::<Size> {
+            bytes: Default::default(),
+        };
+
+        self.raw.ctrl.write(|w| w.setkey().set_bit());
+
+        self.wait_for_cmd()?;
+
+        // as for `get_key`, the command runs to completion, here if the PUF
+        // disagrees with `Size` about the length of key or key code
+        let mut word_buf = [0u8; 4];
+        let mut count_in = 0;
+        let mut count_out = 0;
+        let mut result = Ok(());
+        while self.raw.stat.read().busy().bit_is_set() {
+            if self.raw.stat.read().keyinreq().bit_is_set() {
+                let word = match user_key.get(count_in..count_in + 4) {
+                    Some(bytes) => {
+                        word_buf.copy_from_slice(bytes);
+                        u32::from_ne_bytes(word_buf)
+                    }
+                    None => {
+                        result = Err(Error::CommandFailed);
+                        0
+                    }
+                };
+                self.raw.keyinput.write(|w| unsafe { w.bits(word) });
+                count_in += 4;
+            }
+            if self.raw.stat.read().codeoutavail().bit_is_set() {
+                let word = self.raw.codeoutput.read().bits();
+                match key_code.bytes.get_mut(count_out..count_out + 4) {
+                    Some(bytes) => bytes.copy_from_slice(&word.to_ne_bytes()),
+                    None => result = Err(Error::CommandFailed),
+                }
+                count_out += 4;
+            }
+        }
+        result?;
+        self.check_success()?;
+        Ok(key_code)
     }
 
-    pub fn set_key(
+    /// Wrap a key into a key code with `set_key`, such that `unwrap` can recover it.
+    ///
+    /// The key index must be from 1 to 15.
+    pub fn wrap<Size: KeySize>(
         &self,
-        _key_size: u32,
-        _key_index: u8,
-        _user_key: &[u8],
-        _key_code: &mut [u8],
-    ) -> Result<()> {
-        unimplemented!();
+        key: &GenericArray<u8, Size>,
+        key_index: u8,
+    ) -> Result<KeyCode<Size>> {
+        if key_index == 0 {
+            return Err(Error::InvalidKeyIndex);
+        }
+        self.set_key(key_index, key)
     }
 
     pub fn version(&self) -> u32 {
         self.raw.version.read().bits()
     }
 
-    // Put PUF into reset state.
-    pub fn reset(&self) {
-        unimplemented!();
+    /// Zeroize the PUF, putting it into reset state.
+    ///
+    /// All secrets are cleared and all commands blocked until the next reset
+    /// of the chip: the returned PUF is neither enrolled nor started, and
+    /// `enroll` or `start` fail with `Error::NotAllowed` until then.
+    pub fn reset(self) -> Result<Puf<init_state::Enabled>> {
+        // always allowed
+        self.raw.ctrl.write(|w| w.zeroize().set_bit());
+        while self.raw.stat.read().busy().bit_is_clear()
+            && self.raw.stat.read().error().bit_is_clear()
+        {}
+        while self.raw.stat.read().busy().bit_is_set() {}
+
+        // cf `PUF_Zeroize` in `fsl_puf.c`: the PUF ends in the error state
+        if self.raw.allow.read().bits() != 0 || self.raw.stat.read().error().bit_is_clear() {
+            return Err(Error::ZeroizeFailed);
+        }
+        Ok(Puf {
+            raw: self.raw,
+            _state: init_state::Enabled(()),
+        })
+    }
+
+    fn configure_key<Size: KeySize>(&self, key_index: u8) -> Result<()> {
+        if self.raw.allow.read().allowsetkey().bit_is_clear() {
+            return Err(Error::NotAllowed);
+        }
+        if key_index > 15 {
+            return Err(Error::InvalidKeyIndex);
+        }
+
+        self.raw
+            .keysize
+            .write(|w| unsafe { w.bits(KeyCode::<Size>::HEADER_SIZE as u32) });
+        self.raw
+            .keyindex
+            .write(|w| unsafe { w.bits(key_index as u32) });
+        Ok(())
     }
 }
 // Must enroll once per device.  Enrolling consumes the PUF and device must be restarted.
 impl Puf<init_state::Enabled> {
-    // Enroll a new key for the PUF.  Writes 1192-byte AC to buffer which should be stored in NV memory.
+    // Enroll a new key for the PUF.  Returns the AC, which should be stored in NV memory.
     // Enroll should occur once per device.
-    pub fn enroll(self, ac_buffer: &mut [u8; 1192]) -> Result<Puf<init_state::Enabled<Enrolled>>> {
+    pub fn enroll(self) -> Result<(Puf<init_state::Enabled<Enrolled>>, ActivationCode)> {
         if self.raw.allow.read().allowenroll().bit_is_clear() {
             return Err(Error::NotAllowed);
         }
 
-        ac_buffer.fill(0);
+        let mut ac = ActivationCode([0; ACTIVATION_CODE_LEN]);
 
         self.raw.ctrl.write(|w| w.enroll().set_bit());
 
         self.wait_for_cmd()?;
 
-        self.read_data(ac_buffer);
+        self.read_data(&mut ac.0);
 
         self.check_success()?;
 
-        Ok(Puf {
-            raw: self.raw,
-            _state: init_state::Enabled(Enrolled),
-        })
+        Ok((
+            Puf {
+                raw: self.raw,
+                _state: init_state::Enabled(Enrolled),
+            },
+            ac,
+        ))
     }
 
-    fn do_start(&mut self, ac_buffer: &[u8; 1192]) -> Result<()> {
+    fn do_start(&mut self, ac: &ActivationCode) -> Result<()> {
         self.raw.ctrl.write(|w| w.start().set_bit());
 
         self.wait_for_cmd()?;
@@ -186,7 +406,7 @@ impl Puf<init_state::Enabled> {
         let mut i = 0;
         while self.raw.stat.read().busy().bit_is_set() {
             if self.raw.stat.read().codeinreq().bit_is_set() {
-                word_buf.copy_from_slice(&ac_buffer[i..i + 4]);
+                word_buf.copy_from_slice(&ac.0[i..i + 4]);
                 let word = u32::from_ne_bytes(word_buf);
                 self.raw.codeinput.write(|w| unsafe { w.bits(word) });
                 i += 4;
@@ -199,11 +419,11 @@ impl Puf<init_state::Enabled> {
     /// Starts the PUF using the activation code.
     ///
     /// Fails if the PUF has already been started, e. g. by the ROM.
-    pub fn start(mut self, ac_buffer: &[u8; 1192]) -> Result<Puf<init_state::Enabled<Started>>> {
+    pub fn start(mut self, ac: &ActivationCode) -> Result<Puf<init_state::Enabled<Started>>> {
         if self.raw.allow.read().allowstart().bit_is_clear() {
             Err(Error::NotAllowed)
         } else {
-            self.do_start(ac_buffer)?;
+            self.do_start(ac)?;
 
             Ok(Puf {
                 raw: self.raw,
@@ -216,12 +436,9 @@ impl Puf<init_state::Enabled> {
     ///
     /// If the PUF has already been started, e. g. by the ROM, the start command is no longer
     /// allowed.  In this case, we assume that the PUF has been started.
-    pub fn try_start(
-        mut self,
-        ac_buffer: &[u8; 1192],
-    ) -> Result<Puf<init_state::Enabled<Started>>> {
+    pub fn try_start(mut self, ac: &ActivationCode) -> Result<Puf<init_state::Enabled<Started>>> {
         if self.raw.allow.read().allowstart().bit_is_set() {
-            self.do_start(ac_buffer)?;
+            self.do_start(ac)?;
         }
         Ok(Puf {
             raw: self.raw,
@@ -231,10 +448,13 @@ impl Puf<init_state::Enabled> {
 }
 
 impl Puf<init_state::Enabled<Started>> {
-    pub fn get_key(
+    /// Derive the key of `key_code`, sending it to `key_destination`.
+    ///
+    /// Keys with non-zero index are written to `key`, returning their length.
+    pub fn get_key<Size: KeySize>(
         &self,
         key_destination: raw::puf::keyenable::KEY_A,
-        key_code: &[u8],
+        key_code: &KeyCode<Size>,
         key: &mut [u8],
     ) -> Result<usize> {
         if self.raw.allow.read().allowgetkey().bit_is_clear() {
@@ -248,27 +468,55 @@ impl Puf<init_state::Enabled<Started>> {
         self.raw.ctrl.write(|w| w.getkey().set_bit());
 
         self.wait_for_cmd()?;
+        // the command runs to completion even if a buffer is too small
+        let key_code = key_code.as_bytes();
         let mut word_buf = [0u8; 4];
         let mut count_in = 0;
         let mut count_out = 0;
+        let mut result = Ok(());
         while self.raw.stat.read().busy().bit_is_set() {
             if self.raw.stat.read().codeinreq().bit_is_set() {
-                word_buf.copy_from_slice(&key_code[count_in..count_in + 4]);
-                let word = u32::from_ne_bytes(word_buf);
+                let word = match key_code.get(count_in..count_in + 4) {
+                    Some(bytes) => {
+                        word_buf.copy_from_slice(bytes);
+                        u32::from_ne_bytes(word_buf)
+                    }
+                    None => {
+                        result = Err(Error::InvalidKeyCode);
+                        0
+                    }
+                };
                 self.raw.codeinput.write(|w| unsafe { w.bits(word) });
                 count_in += 4;
             }
             if self.raw.stat.read().keyoutavail().bit_is_set() {
                 self.raw.keyindex.read().bits();
                 let word = self.raw.keyoutput.read().bits();
-                key[count_out..count_out + 4].copy_from_slice(&word.to_ne_bytes());
+                match key.get_mut(count_out..count_out + 4) {
+                    Some(bytes) => bytes.copy_from_slice(&word.to_ne_bytes()),
+                    None => result = Err(Error::BufferTooSmall),
+                }
                 count_out += 4;
             }
         }
+        result?;
         self.check_success()?;
 
         Ok(count_out)
     }
+
+    /// Recover a key wrapped with `wrap`.
+    pub fn unwrap<Size: KeySize>(
+        &self,
+        key_code: &KeyCode<Size>,
+    ) -> Result<GenericArray<u8, Size>> {
+        if !key_code.is_exportable() {
+            return Err(Error::InvalidKeyIndex);
+        }
+        let mut key = GenericArray::default();
+        self.get_key(raw::puf::keyenable::KEY_A::NONE, key_code, &mut key)?;
+        Ok(key)
+    }
 }
 
 impl<State> core::fmt::Debug for Puf<State> {
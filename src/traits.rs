@@ -1,5 +1,6 @@
 use core::ops::Deref;
 
+pub use aead;
 pub use cipher;
 pub use digest;
 
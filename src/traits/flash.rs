@@ -1,16 +1,18 @@
 use generic_array::{ArrayLength, GenericArray};
 
 /// Flash operation error
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Error {
     /// Flash controller is not done yet
     Busy,
     /// Error detected (by command execution, or because no command could be executed)
     Illegal,
-    /// Set during read if ECC decoding logic detects correctable or uncorrectable error
+    /// ECC decoding logic detected an uncorrectable error during read
     EccError,
     /// (Legal) command failed
     Failure,
+    /// Erase or program of a page locked with `FlashGordon::lock`
+    WriteProtected,
 }
 
 //     /// Flash program and erase controller failed to unlock
@@ -23,31 +25,50 @@ pub enum Error {
 //     Busy
 // }
 
+/// Outcome of a successful read
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EccStatus {
+    /// No ECC event
+    Clean,
+    /// ECC decoding logic corrected (single bit) errors
+    Corrected,
+}
+
 /// A type alias for the result of a Flash operation.
-pub type Result = core::result::Result<(), Error>;
+pub type Result<T = ()> = core::result::Result<T, Error>;
 
 // pub trait FlashOps: Locking + WriteErase + Read {}
 
 pub trait Read<ReadSize: ArrayLength> {
     // Address alignment?
-    fn read_native(&self, address: usize, array: &mut GenericArray<u8, ReadSize>);
+    fn read_native(
+        &self,
+        address: usize,
+        array: &mut GenericArray<u8, ReadSize>,
+    ) -> Result<EccStatus>;
 
     /// read a buffer of bytes from memory
     /// checks that the address and buffer size are multiples of native
     /// FLASH ReadSize.
-    fn read(&self, address: usize, buf: &mut [u8]) {
-        // TODO: offer a version without restrictions?
-        // can round down address, round up buffer length,
-        // but where to get the buffer from?
+    ///
+    /// For reads without these restrictions, see `FlashGordon::read_unaligned`.
+    ///
+    /// Returns `EccStatus::Corrected` if errors were corrected in any of the native reads.
+    fn read(&self, address: usize, buf: &mut [u8]) -> Result<EccStatus> {
         assert!(buf.len() % ReadSize::to_usize() == 0);
         assert!(address % ReadSize::to_usize() == 0);
 
+        let mut status = EccStatus::Clean;
         for i in (0..buf.len()).step_by(ReadSize::to_usize()) {
-            self.read_native(
+            if self.read_native(
                 address + i,
                 GenericArray::from_mut_slice(&mut buf[i..i + ReadSize::to_usize()]),
-            );
+            )? == EccStatus::Corrected
+            {
+                status = EccStatus::Corrected;
+            }
         }
+        Ok(status)
     }
 }
 
@@ -127,6 +127,36 @@ pub struct ClocksSupport32KhzFroToken {
     pub(crate) __: (),
 }
 
-pub mod flash_state {}
+/// Encodes whether pages of `FlashGordon` are write-protected
+pub mod flash_state {
+    pub trait LockState {}
+
+    /// No pages are write-protected
+    pub struct Unlocked;
+    impl LockState for Unlocked {}
+
+    /// Some pages are write-protected until reset
+    pub struct Locked;
+    impl LockState for Locked {}
+}
+
+/// Encodes whether writing CMPA seals it
+pub mod seal_state {
+    pub trait SealState {
+        const SEAL: bool;
+    }
+
+    /// CMPA can be written again
+    pub struct Unsealed;
+    impl SealState for Unsealed {
+        const SEAL: bool = false;
+    }
+
+    /// CMPA is sealed (locked) for good
+    pub struct Sealed;
+    impl SealState for Sealed {
+        const SEAL: bool = true;
+    }
+}
 
 pub mod reg_proxy;